@@ -48,7 +48,7 @@ case $ktest_arch in
 
 	require-kernel-append console=hvc0
 	;;
-    powerpc)
+    powerpc|ppc64|ppc64le)
 	require-kernel-config ADVANCED_OPTIONS
 
 	have_kvmguest=1
@@ -57,16 +57,60 @@ case $ktest_arch in
 
 	require-kernel-append console=hvc0
 	;;
-    mips)
+    mips|mipsel)
+	# endianness is required by ktest itself, from the arch
 	require-kernel-config MIPS_MALTA
-	require-kernel-config CPU_MIPS${BITS}_R2
-	require-kernel-config CPU_BIG_ENDIAN=y
-	require-kernel-config CPU_LITTLE_ENDIAN=n
+	require-kernel-config CPU_MIPS32_R2
 	require-kernel-config 32BIT
 
 	have_virtio=1
 	ktest_storage_bus=piix4-ide
 
+	require-kernel-append console=hvc0
+	;;
+    mips64|mips64el)
+	require-kernel-config MIPS_MALTA
+	require-kernel-config CPU_MIPS64_R2
+	require-kernel-config 64BIT
+
+	have_virtio=1
+	ktest_storage_bus=piix4-ide
+
+	require-kernel-append console=hvc0
+	;;
+    riscv64|riscv)
+	require-kernel-config 64BIT
+	require-kernel-config SOC_VIRT
+	require-kernel-config PCI_HOST_GENERIC
+	require-kernel-config RTC_DRV_GOLDFISH
+
+	have_virtio=1
+
+	require-kernel-append console=hvc0
+	;;
+    arm|armv7l|armv8l)
+	require-kernel-config ARCH_VIRT
+	require-kernel-config PCI_HOST_GENERIC
+	require-kernel-config RTC_DRV_PL031
+
+	have_virtio=1
+
+	require-kernel-append console=hvc0
+	;;
+    s390x|s390)
+	require-kernel-config S390_GUEST	# virtio-ccw transport
+
+	have_virtio=1
+	ktest_storage_bus=virtio-blk
+
+	require-kernel-append console=hvc0
+	;;
+    loongarch64|loongarch)
+	require-kernel-config 64BIT
+	require-kernel-config RTC_DRV_LOONGSON
+
+	have_virtio=1
+
 	require-kernel-append console=hvc0
 	;;
     *)
@@ -196,6 +196,51 @@ set_watchdog()
     echo WATCHDOG $ktest_timeout
 }
 
+# With --network user, ktest passes its ssh key with fw_cfg and forwards a
+# host port to port 22 of qemu's user mode network
+setup_ssh()
+{
+    local keys=/sys/firmware/qemu_fw_cfg/by_name/opt/ktest/authorized_keys/raw
+    local dev
+
+    [[ -f $keys ]] || return 0
+
+    mkdir -p /root/.ssh
+    chmod 700 /root/.ssh
+    cat $keys >> /root/.ssh/authorized_keys
+    chmod 600 /root/.ssh/authorized_keys
+
+    for dev in /sys/class/net/*; do
+	dev=$(basename $dev)
+	[[ $dev = lo ]] && continue
+
+	ip link set $dev up
+	ip addr add 10.0.2.15/24 dev $dev
+	ip route add default via 10.0.2.2
+	break
+    done
+
+    if [[ -x /usr/sbin/sshd ]]; then
+	mkdir -p /run/sshd
+	ssh-keygen -A >/dev/null
+	/usr/sbin/sshd
+    fi
+}
+
+# For VM snapshots: tell ktest the guest is booted, then run the test it sends
+# on the console, a path relative to the directory ktest runs in. Kernels booted
+# for a snapshot have ktest.wait_for_test on their command line, which makes
+# run-tests end up here whichever test the guest init starts.
+# See `ktest boot --snapshot-after-boot`
+wait_for_test()
+{
+    local test
+
+    echo "ktest: ready"
+    read -r test
+    ktest_waited=1 "$test" run-tests $("$test" list-tests)
+}
+
 run_test()
 {
     local test=test_$1
@@ -213,6 +258,8 @@ run_tests()
     local tests_passed=()
     local tests_failed=()
 
+    setup_ssh
+
     echo
     echo "Running tests $@"
     echo
@@ -269,6 +316,76 @@ list_tests()
     declare -F|sed -ne '/ test_/ s/.*test_// p'
 }
 
+json_string()
+{
+    local s=$1
+
+    s=${s//\\/\\\\}
+    s=${s//\"/\\\"}
+    s=${s//$'\n'/\\n}
+    s=${s//$'\r'/\\r}
+    s=${s//$'\t'/\\t}
+
+    printf '"%s"' "$s"
+}
+
+json_optional()
+{
+    if [[ -n $1 ]]; then
+	json_string "$1"
+    else
+	printf null
+    fi
+}
+
+json_number()
+{
+    if [[ -n $1 ]]; then
+	printf '%d' "$1"
+    else
+	printf null
+    fi
+}
+
+json_array()
+{
+    local sep=""
+
+    printf '['
+    for i in "$@"; do
+	printf '%s' "$sep"
+	json_string "$i"
+	sep=","
+    done
+    printf ']'
+}
+
+# Versioned replacement for the key=value deps output, parsed by ktest's
+# TestDeps; bump version when making incompatible changes.
+deps_json()
+{
+    local timeout=""
+
+    if [[ -n $ktest_timeout ]]; then
+	timeout=$((ktest_timeout * ktest_timeout_multiplier))
+    fi
+
+    printf '{"version":1'
+    printf ',"arch":';			json_optional "${ktest_arch-}"
+    printf ',"cpus":';			json_number "$ktest_cpus"
+    printf ',"mem":';			json_optional "$ktest_mem"
+    printf ',"timeout":';		json_number "$timeout"
+    printf ',"kernel_append":';		json_array "${ktest_kernel_append[@]}"
+    printf ',"kernel_make_append":';	json_array "${ktest_kernel_make_append[@]}"
+    printf ',"storage_bus":';		json_optional "$ktest_storage_bus"
+    printf ',"images":';		json_array "${ktest_images[@]}"
+    printf ',"scratch_dev_sizes":';	json_array "${ktest_scratch_dev_sizes[@]}"
+    printf ',"make_install":';		json_array "${ktest_make_install[@]}"
+    printf ',"kernel_config_require":';	json_array "${ktest_kernel_config_require[@]}"
+    printf ',"qemu_append":';		json_array "${ktest_qemu_append[@]}"
+    printf '}\n'
+}
+
 main()
 {
     if [[ $BASH_ARGC = 0 ]]; then
@@ -280,6 +397,11 @@ main()
 
     case $arg in
 	deps)
+	    if [[ ${1-} = json ]]; then
+		deps_json
+		exit 0
+	    fi
+
 	    echo "ktest_arch=$ktest_arch"
 	    echo "ktest_cpus=$ktest_cpus"
 	    echo "ktest_mem=$ktest_mem"
@@ -297,7 +419,14 @@ main()
 	    list_tests
 	    ;;
 	run-tests)
-	    run_tests "$@"
+	    if [[ -z ${ktest_waited-} ]] && grep -qw ktest.wait_for_test /proc/cmdline 2>/dev/null; then
+		wait_for_test
+	    else
+		run_tests "$@"
+	    fi
+	    ;;
+	wait-for-test)
+	    wait_for_test
 	    ;;
 	*)
 	    #usage
@@ -0,0 +1,200 @@
+use crate::config::Config;
+use crate::manifest::Manifest;
+use crate::{Context, Error, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::*;
+
+/// Bundle the installed kernel into a gzip compressed tarball.
+///
+/// Only kernels matching their build manifest are exported. With
+/// `strip_debug` vmlinux is stored without debug info, and the manifest in the
+/// archive is updated to match.
+#[instrument(level = "debug", skip(config))]
+pub fn export(config: &Config, archive: &Path, strip_debug: bool) -> Result {
+    let mut manifest = crate::manifest::verify(config)?;
+    let base = config.make.kernel_bin_dir();
+
+    let file = std::fs::File::create(archive)
+        .context(format!("Failed to create {}", archive.display()))?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, flate2::Compression::default()));
+    tar.follow_symlinks(false);
+
+    let mut stripped = None;
+    if strip_debug {
+        let path = config.make.out_dir().join("vmlinux.stripped");
+        strip(config, &base.join("vmlinux"), &path)?;
+        if let Some(artifact) = manifest.artifacts.iter_mut().find(|a| a.role == "vmlinux") {
+            (artifact.size, artifact.sha256) = crate::manifest::checksum(&path)?;
+        }
+        stripped = Some(path);
+    }
+
+    for artifact in &manifest.artifacts {
+        let src = match &stripped {
+            Some(path) if artifact.role == "vmlinux" => path.clone(),
+            _ => base.join(&artifact.path),
+        };
+        tar.append_path_with_name(&src, &artifact.path)
+            .context(format!(
+                "Failed to add {} to archive",
+                artifact.path.display()
+            ))?;
+    }
+
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    );
+    tar.append_data(&mut header, "manifest.json", data.as_slice())?;
+
+    tar.into_inner()?.finish()?;
+    if let Some(path) = stripped {
+        drop(std::fs::remove_file(path));
+    }
+
+    info!(
+        "Exported {} artifacts of {} to {}",
+        manifest.artifacts.len(),
+        manifest.release,
+        archive.display()
+    );
+    Ok(())
+}
+
+/// Unpack an archive made by [`export`] under the out dir and use it as the
+/// kernel bin dir, switching to the arch it was built for.
+#[instrument(level = "debug", skip(config))]
+pub fn import(config: &mut Config, archive: &Path) -> Result<Manifest> {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context(format!("Invalid archive name {}", archive.display()))?;
+    let name = name
+        .strip_suffix(".tar.gz")
+        .or_else(|| name.strip_suffix(".tgz"))
+        .unwrap_or(name);
+    let dir = config.make.out_dir().join("archives").join(name);
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).context(format!("Failed to remove {}", dir.display()))?;
+    }
+    std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+
+    let file =
+        std::fs::File::open(archive).context(format!("Failed to open {}", archive.display()))?;
+    tar::Archive::new(GzDecoder::new(file))
+        .unpack(&dir)
+        .context(format!("Failed to unpack {}", archive.display()))?;
+    debug!("Unpacked {} to {}", archive.display(), dir.display());
+
+    config.make.kernel_archive_dir = Some(dir);
+    let manifest = Manifest::read(config)?;
+    if !manifest.arch.is_empty() {
+        config.make.arch = Some(manifest.arch.parse()?);
+    }
+    manifest.verify(config)?;
+
+    Ok(manifest)
+}
+
+/// Strip debug info with the `strip` of the configured cross toolchain.
+fn strip(config: &Config, src: &Path, dst: &Path) -> Result {
+    let prefix = config
+        .make
+        .extra_make_args
+        .iter()
+        .find_map(|a| a.strip_prefix("CROSS_COMPILE="))
+        .unwrap_or_default();
+    let strip = PathBuf::from(format!("{prefix}strip"));
+
+    debug!(
+        "Running {} --strip-debug {}",
+        strip.display(),
+        src.display()
+    );
+    let status = Command::new(&strip)
+        .arg("--strip-debug")
+        .arg("-o")
+        .arg(dst)
+        .arg(src)
+        .status()
+        .context(format!("Failed to execute {}", strip.display()))?;
+    if !status.success() {
+        return Err(Error::new("Failed to strip vmlinux").set_exit_code(status.code()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Arch;
+
+    fn config(name: &str) -> Config {
+        let mut config = Config::embedded();
+        config.make.out_dir = std::env::temp_dir()
+            .join(format!("ktest-{name}-{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        config
+    }
+
+    #[test]
+    fn export_import() {
+        let mut src = config("export");
+        src.make.arch = Some(Arch::Aarch64);
+        let base = src.make.kernel_bin_dir();
+        std::fs::create_dir_all(base.join("lib/modules/6.8/kernel")).unwrap();
+        std::fs::write(base.join("vmlinuz"), "image").unwrap();
+        std::fs::write(base.join("lib/modules/6.8/kernel/a.ko"), "a").unwrap();
+        let manifest = Manifest::collect(&src, "6.8", Vec::new()).unwrap();
+        manifest.write(&src).unwrap();
+
+        let archive = src.make.out_dir().join("kernel-6.8.tar.gz");
+        export(&src, &archive, false).unwrap();
+
+        let mut dst = config("import");
+        dst.make.arch = Some(Arch::X86_64);
+        let imported = import(&mut dst, &archive).unwrap();
+
+        assert_eq!(dst.make.arch, Some(Arch::Aarch64));
+        assert_eq!(
+            dst.make.kernel_bin_dir(),
+            dst.make.out_dir().join("archives").join("kernel-6.8")
+        );
+        assert_eq!(imported.release, "6.8");
+        assert_eq!(imported.artifacts, manifest.artifacts);
+        assert_eq!(
+            std::fs::read_to_string(dst.make.kernel_bin_dir().join("vmlinuz")).unwrap(),
+            "image"
+        );
+
+        // importing again replaces the unpacked kernel
+        std::fs::write(dst.make.kernel_bin_dir().join("stale"), "").unwrap();
+        import(&mut dst, &archive).unwrap();
+        assert!(!dst.make.kernel_bin_dir().join("stale").exists());
+
+        std::fs::remove_dir_all(src.make.out_dir()).unwrap();
+        std::fs::remove_dir_all(dst.make.out_dir()).unwrap();
+    }
+
+    #[test]
+    fn export_requires_manifest() {
+        let mut config = config("export-unbuilt");
+        config.make.arch = Some(Arch::X86_64);
+        let archive = std::env::temp_dir().join("ktest-export-unbuilt.tar.gz");
+        assert!(export(&config, &archive, false).is_err());
+        assert!(!archive.exists());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::boot::{Outcome, QemuCmd};
+use crate::config::Config;
+use crate::source::SourceInfo;
+use crate::{Context, Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// Parameters of the bisect ktest is driving, used to resume it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BisectState {
+    pub good: String,
+    pub bad: String,
+    pub test: PathBuf,
+}
+
+fn bisect_dir(config: &Config) -> PathBuf {
+    config.make.out_dir().join("bisect")
+}
+
+fn state_file(config: &Config) -> PathBuf {
+    bisect_dir(config).join("state.json")
+}
+
+pub fn log_file(config: &Config) -> PathBuf {
+    bisect_dir(config).join("log")
+}
+
+fn git_output(config: &Config, args: &[&str]) -> Result<String> {
+    crate::git::output(&config.make.kernel_dir, args)
+}
+
+fn bisect_in_progress(config: &Config) -> Result<bool> {
+    let start = git_output(config, &["rev-parse", "--git-path", "BISECT_START"])?;
+    Ok(Path::new(&config.make.kernel_dir)
+        .join(start.trim())
+        .exists())
+}
+
+/// Start, or resume, a bisect between `state.good` and `state.bad`.
+///
+/// Each step builds the kernel and runs the test. Build failures and
+/// infrastructure failures are skipped, as they say nothing about the test.
+#[instrument(level = "debug", skip(config, args))]
+pub fn bisect<I, S>(config: &Config, state: &BisectState, reset: bool, args: I) -> Result
+where
+    I: IntoIterator<Item = S> + core::fmt::Debug + Clone,
+    S: AsRef<std::ffi::OsStr>,
+{
+    std::fs::create_dir_all(bisect_dir(config)).context("Failed to create bisect dir")?;
+
+    let old_state = std::fs::read(state_file(config))
+        .ok()
+        .and_then(|s| serde_json::from_slice::<BisectState>(&s).ok());
+
+    if bisect_in_progress(config)? {
+        if old_state.as_ref() == Some(state) && !reset {
+            info!("Resuming bisect");
+            println!("Resuming bisect of {}", state.test.display());
+        } else if reset {
+            git_output(config, &["bisect", "reset"])?;
+            start(config, state)?;
+        } else {
+            return Err(Error::new(
+                "A different bisect is already in progress in the kernel tree, use --reset to restart it",
+            ));
+        }
+    } else {
+        start(config, state)?;
+    }
+
+    loop {
+        let rev = git_output(config, &["rev-parse", "HEAD"])?
+            .trim()
+            .to_string();
+        println!("Bisecting: testing {rev}");
+
+        let (kernel, result, mark) = match crate::build::build(config, args.clone()) {
+            Ok(release) => {
+                let kernel = SourceInfo::read(config)?
+                    .map(|s| s.to_string())
+                    .unwrap_or(release);
+                let outcome = QemuCmd::new(config)?.run_test(config.qemu.timeout())?;
+                let mark = match outcome {
+                    Outcome::Passed => "good",
+                    o if o.is_infra_failure() => "skip",
+                    _ => "bad",
+                };
+                (kernel, outcome.to_string(), mark)
+            }
+            Err(e) => {
+                warn!("Build failed: {e}");
+                (rev.clone(), "build failed".to_string(), "skip")
+            }
+        };
+
+        log_step(config, &rev, &kernel, &result, mark)?;
+        println!("{kernel}: {result}, marking {mark}");
+
+        let out = crate::git::git(&config.make.kernel_dir)
+            .args(["bisect", mark])
+            .output()
+            .context("Failed to execute git bisect")?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        trace!("git bisect {mark}: {stdout}");
+
+        if stdout.contains("is the first bad commit") {
+            println!("{}", stdout.trim());
+            break;
+        }
+        if !out.status.success() {
+            // e.g. only skipped commits are left
+            println!("{}", stdout.trim());
+            println!("{}", String::from_utf8_lossy(&out.stderr).trim());
+            break;
+        }
+    }
+
+    git_output(config, &["bisect", "reset"])?;
+    drop(std::fs::remove_file(state_file(config)));
+    println!("Bisect log: {}", log_file(config).display());
+
+    Ok(())
+}
+
+fn start(config: &Config, state: &BisectState) -> Result {
+    info!("Starting bisect, good: {}, bad: {}", state.good, state.bad);
+    git_output(config, &["bisect", "start", &state.bad, &state.good])?;
+
+    std::fs::write(state_file(config), serde_json::to_vec_pretty(state)?)
+        .context("Failed to write bisect state")?;
+
+    let mut log = std::fs::File::create(log_file(config)).context("Failed to create bisect log")?;
+    writeln!(
+        log,
+        "# bisect {} good: {} bad: {}",
+        state.test.display(),
+        state.good,
+        state.bad
+    )?;
+
+    Ok(())
+}
+
+fn log_step(config: &Config, rev: &str, kernel: &str, result: &str, mark: &str) -> Result {
+    let mut log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file(config))
+        .context("Failed to open bisect log")?;
+    writeln!(
+        log,
+        "{} {rev} {mark} {result}: {kernel}",
+        crate::timestamp()
+    )?;
+    Ok(())
+}
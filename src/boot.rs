@@ -1,11 +1,24 @@
-use crate::config::Config;
+use crate::config::{Arch, BootMode, Config, Network};
+use crate::crash::{CrashWatch, Panic};
+use crate::deps::TestDeps;
+use crate::kconfig::KconfigValue;
+use crate::ssh::PortForward;
 use crate::{Context, Error, Result};
-use std::process::Command;
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use tracing::*;
 
 pub struct QemuCmd {
     pub cmd: Command,
+    crash: CrashWatch,
+    watcher: Option<(JoinHandle<Option<Panic>>, Arc<AtomicBool>)>,
+    ssh: Option<PortForward>,
+    /// Set when the guest kernel panicked.
+    pub panic: Option<Panic>,
 }
 
 impl QemuCmd {
@@ -15,13 +28,6 @@ impl QemuCmd {
         let mut cmd = Command::new(config.qemu_path().context("Missing qemu executable")?);
         cmd.args(config.qemu_args());
 
-        let mut kernel_args: Vec<String> =
-            config.qemu_kernel_args().map(|s| s.to_string()).collect();
-        match config.qemu.storage_bus.as_str() {
-            "virtio-blk" => kernel_args.push("root=/dev/vda".to_string()),
-            _ => kernel_args.push("root=/dev/sda".to_string()),
-        }
-
         cmd.arg("-m").arg(format!(
             "{mem},slots=8,maxmem={maxmem}",
             mem = config.qemu.mem(),
@@ -29,9 +35,63 @@ impl QemuCmd {
         ));
         cmd.arg("-smp")
             .arg(format!("{cpus}", cpus = config.qemu.cpus()));
-        cmd.arg("-kernel")
-            .arg(config.make.kernel_bin_dir().join("vmlinuz"));
-        cmd.arg("-append").arg(kernel_args.join(" "));
+        let firmware = config
+            .qemu
+            .firmware(config.make.arch.as_ref().context("Arch missing")?)
+            .cloned()
+            .unwrap_or_default();
+        // the ssh port is forwarded once qemu runs, see `PortForward::start`
+        let ssh = (config.qemu.network == Network::User).then(|| PortForward::new(config));
+        if ssh.is_some() {
+            cmd.arg("-netdev").arg("user,id=net0");
+            cmd.arg("-device").arg("virtio-net,netdev=net0");
+            cmd.arg("-fw_cfg").arg(format!(
+                "name={},file={}",
+                crate::ssh::FW_CFG_KEY,
+                crate::ssh::public_key(config)?.display()
+            ));
+        }
+
+        let dtb = config.make.kernel_bin_dir().join("dtb");
+        if dtb.exists() {
+            cmd.arg("-dtb").arg(dtb);
+        }
+
+        match config.qemu.boot_mode {
+            BootMode::Direct => {
+                if let Some(bios) = &firmware.bios {
+                    cmd.arg("-bios").arg(bios);
+                }
+                cmd.arg("-kernel")
+                    .arg(config.make.kernel_bin_dir().join("vmlinuz"));
+                cmd.arg("-append").arg(kernel_cmdline(config));
+            }
+            BootMode::Uefi => {
+                let code = firmware
+                    .code
+                    .context("No UEFI firmware configured, set qemu.<arch>.firmware.code")?;
+                cmd.arg("-drive").arg(format!(
+                    "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                    code.display()
+                ));
+                if let Some(vars) = &firmware.vars {
+                    let copy = config.make.out_dir().join("vm").join("efivars.fd");
+                    std::fs::copy(vars, &copy)
+                        .context(format!("Failed to copy {}", vars.display()))?;
+                    cmd.arg("-drive").arg(format!(
+                        "if=pflash,format=raw,unit=1,file={}",
+                        copy.display()
+                    ));
+                }
+
+                let esp = make_esp(config)?;
+                cmd.arg("-drive").arg(format!(
+                    "if=none,id=esp,format=raw,readonly=on,file={}",
+                    esp.display()
+                ));
+                cmd.arg("-device").arg("virtio-blk-pci,drive=esp");
+            }
+        }
         cmd.arg("-serial").arg(format!(
             "unix:{},server,nowait",
             config.make.out_dir().join("vm").join("kgdb").display()
@@ -44,8 +104,26 @@ impl QemuCmd {
             "unix:{},server,nowait",
             config.make.out_dir().join("vm").join("gdb").display()
         ));
+        cmd.arg("-qmp").arg(format!(
+            "unix:{},server,nowait",
+            crate::crash::qmp_socket(config).display()
+        ));
+
+        // keep a panicked guest around for dump-guest-memory; guest reboots
+        // end the VM, they are not supported
+        cmd.arg("-no-reboot");
+        if let Some(device) = pvpanic_device(config) {
+            cmd.arg("-device").arg(device);
+        }
+        cmd.arg("-action").arg("panic=pause");
 
-        Ok(Self { cmd })
+        Ok(Self {
+            cmd,
+            crash: CrashWatch::new(config),
+            watcher: None,
+            ssh,
+            panic: None,
+        })
     }
 
     fn setup_dirs(config: &Config) -> Result {
@@ -58,6 +136,80 @@ impl QemuCmd {
     }
 
     pub fn run(&mut self) -> Result {
+        let status = self.spawn()?.wait().context("Failed to wait for qemu")?;
+        self.join_watcher();
+        if self.panic.is_some() {
+            return Outcome::Panic.into_result();
+        }
+
+        if !status.success() {
+            info!("Failed to run qemu: {}", status);
+            Err(Error::new("Failed to run qemu").set_exit_code(status.code()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run a test, killing qemu if it is still running after `timeout`.
+    ///
+    /// Only failing to start qemu is reported as an error, everything else is
+    /// described by the returned [`Outcome`].
+    pub fn run_test(&mut self, timeout: Option<Duration>) -> Result<Outcome> {
+        let mut child = self.spawn()?;
+        self.wait(&mut child, timeout)
+    }
+
+    /// Start qemu, watching for guest panics in the background.
+    pub fn spawn(&mut self) -> Result<Child> {
+        self.log_cmd();
+        let mut child = self.cmd.spawn().context("Failed to run qemu")?;
+
+        let exited = Arc::new(AtomicBool::new(false));
+        self.watcher = Some((self.crash.clone().start(exited.clone()), exited));
+
+        if let Some(ssh) = &mut self.ssh {
+            if let Err(e) = ssh.start() {
+                drop(child.kill());
+                drop(child.wait());
+                self.join_watcher();
+                return Err(e);
+            }
+        }
+        Ok(child)
+    }
+
+    /// Wait for qemu to exit, killing it if it is still running after
+    /// `timeout`.
+    pub fn wait(&mut self, child: &mut Child, timeout: Option<Duration>) -> Result<Outcome> {
+        let outcome = wait_for_outcome(child, timeout)?;
+        self.join_watcher();
+        // qemu exits normally after a panic was dumped
+        Ok(match outcome {
+            Outcome::Timeout => outcome,
+            _ if self.panic.is_some() => Outcome::Panic,
+            _ => outcome,
+        })
+    }
+
+    fn join_watcher(&mut self) {
+        if let Some((watcher, exited)) = self.watcher.take() {
+            exited.store(true, Ordering::Relaxed);
+            self.panic = watcher.join().unwrap_or_default();
+            if let Some(dir) = self.panic.as_ref().and_then(|p| p.dump.as_ref()) {
+                eprintln!("Saved crash dump to {}", dir.display());
+            }
+        }
+    }
+
+    /// The arguments, for comparing qemu command lines.
+    pub fn args(&self) -> Vec<String> {
+        self.cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn log_cmd(&self) {
         debug!(
             "Running {} {}",
             self.cmd
@@ -70,13 +222,250 @@ impl QemuCmd {
                 .collect::<Vec<_>>()
                 .join(" ")
         );
-        let status = self.cmd.status().context("Failed to run qemu")?;
+    }
+}
 
-        if !status.success() {
-            info!("Failed to run qemu: {}", status);
-            Err(Error::new("Failed to run qemu").set_exit_code(status.code()))
-        } else {
-            Ok(())
+impl Drop for QemuCmd {
+    fn drop(&mut self) {
+        if let Some(ssh) = &mut self.ssh {
+            ssh.stop();
+        }
+    }
+}
+
+fn wait_for_outcome(child: &mut Child, timeout: Option<Duration>) -> Result<Outcome> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for qemu")? {
+            break status;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            info!("Test timed out after {:?}, killing qemu", timeout.unwrap());
+            drop(child.kill());
+            drop(child.wait());
+            return Ok(Outcome::Timeout);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    debug!("qemu exited: {}", status);
+    Ok(match status.code() {
+        Some(0) => Outcome::Passed,
+        Some(code) => Outcome::Failed(code),
+        None => Outcome::Crashed,
+    })
+}
+
+fn pvpanic_bus_kconfig(device: &str) -> &'static str {
+    if device.ends_with("-pci") {
+        "PVPANIC_PCI"
+    } else {
+        "PVPANIC_MMIO"
+    }
+}
+
+/// The pvpanic device to give the guest, if the arch has one and the
+/// installed kernel was built with its driver.
+pub fn pvpanic_device(config: &Config) -> Option<&'static str> {
+    let device = config.make.arch?.pvpanic_device()?;
+    let kconfig = crate::kconfig::read_config(&config.make.kernel_bin_dir().join("config")).ok()?;
+    let built = |key| {
+        kconfig
+            .get(key)
+            .is_some_and(|v| matches!(v, KconfigValue::Yes | KconfigValue::Module))
+    };
+    (built("PVPANIC") && built(pvpanic_bus_kconfig(device))).then_some(device)
+}
+
+/// The kernel command line for the configured arch and storage bus.
+pub fn kernel_cmdline(config: &Config) -> String {
+    let mut kernel_args: Vec<String> = config.qemu_kernel_args().map(|s| s.to_string()).collect();
+    // without pvpanic, reboot right away, which qemu turns into an exit with
+    // -no-reboot and which is then taken for a panic
+    if pvpanic_device(config).is_none() {
+        kernel_args.push("panic=-1".to_string());
+    }
+    match config.qemu.storage_bus.as_str() {
+        "virtio-blk" => kernel_args.push("root=/dev/vda".to_string()),
+        _ => kernel_args.push("root=/dev/sda".to_string()),
+    }
+    kernel_args.join(" ")
+}
+
+/// Build an EFI system partition holding the kernel as the default boot
+/// loader, plus a `startup.nsh` for when the firmware drops to the UEFI shell.
+///
+/// The firmware starts the boot loader without arguments, so the kernel needs
+/// its command line built in, see [`update_config_for_qemu`].
+fn make_esp(config: &Config) -> Result<PathBuf> {
+    let arch = config.make.arch.context("Arch missing")?;
+    let boot_file = arch
+        .efi_boot_file()
+        .context(format!("UEFI boot is not supported on {arch}"))?;
+    let kernel = config.make.kernel_bin_dir().join("vmlinuz");
+    let dir = config.make.out_dir().join("vm");
+    let esp = dir.join("esp.img");
+    let startup = dir.join("startup.nsh");
+
+    std::fs::write(
+        &startup,
+        format!("\\EFI\\BOOT\\{boot_file} {}\r\n", kernel_cmdline(config)),
+    )
+    .context("Failed to write startup.nsh")?;
+
+    // room for the kernel and the file system itself
+    let kernel_kib = std::fs::metadata(&kernel)
+        .context(format!("Failed to read {}", kernel.display()))?
+        .len()
+        / 1024;
+    let size_kib = (kernel_kib + 8 * 1024).to_string();
+
+    if esp.exists() {
+        std::fs::remove_file(&esp).context("Failed to remove old EFI system partition")?;
+    }
+    let kernel_dst = format!("::/EFI/BOOT/{boot_file}");
+    tool(Command::new("mkfs.vfat").arg("-C").arg(&esp).arg(size_kib))?;
+    tool(
+        Command::new("mmd")
+            .arg("-i")
+            .arg(&esp)
+            .args(["::/EFI", "::/EFI/BOOT"]),
+    )?;
+    tool(
+        Command::new("mcopy")
+            .arg("-i")
+            .arg(&esp)
+            .arg(&kernel)
+            .arg(kernel_dst),
+    )?;
+    tool(
+        Command::new("mcopy")
+            .arg("-i")
+            .arg(&esp)
+            .arg(&startup)
+            .arg("::/startup.nsh"),
+    )?;
+
+    Ok(esp)
+}
+
+fn tool(cmd: &mut Command) -> Result {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    debug!(
+        "Running {program} {}",
+        cmd.get_args()
+            .map(|a| a.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+
+    let out = cmd
+        .output()
+        .context(format!("Failed to execute {program}"))?;
+    if !out.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&out.stderr));
+        return Err(Error::new(format!("Failed to run {program}")).set_exit_code(out.status.code()));
+    }
+    Ok(())
+}
+
+/// Require the kconfig the configured network and boot mode need, and ask
+/// for the pvpanic driver guest panics are reported with.
+///
+/// The ssh key for the guest is passed with fw_cfg, so `--network user` is
+/// only available on machines that have it. UEFI boots start the kernel's EFI
+/// stub, which gets no command line from the firmware and uses the built in
+/// one instead.
+pub fn update_config_for_qemu(config: &mut Config) -> Result {
+    if config.qemu.network == Network::User {
+        let arch = config.make.arch.context("Arch missing")?;
+        if !arch.has_fw_cfg() {
+            return Err(Error::new(format!(
+                "--network user is not supported on {arch}, the machine has no fw_cfg to pass the ssh key"
+            )));
+        }
+        for key in ["VIRTIO_NET", "FW_CFG_SYSFS"] {
+            config
+                .make
+                .require_kconfig(key.to_string(), KconfigValue::Yes, "--network user");
+        }
+    }
+
+    // pvpanic drivers only exist since v5.12, older kernels still have to
+    // build and boot, e.g. when bisecting
+    if let Some(device) = config.make.arch.and_then(|a| a.pvpanic_device()) {
+        for key in ["PVPANIC", pvpanic_bus_kconfig(device)] {
+            config.make.want_kconfig(key, KconfigValue::Yes);
+        }
+    }
+
+    if config.qemu.boot_mode != BootMode::Uefi {
+        return Ok(());
+    }
+
+    let source = "--boot-mode uefi";
+    let arch = config.make.arch.context("Arch missing")?;
+    config
+        .make
+        .require_kconfig("EFI".to_string(), KconfigValue::Yes, source);
+    config
+        .make
+        .require_kconfig("EFI_STUB".to_string(), KconfigValue::Yes, source);
+    if matches!(arch, Arch::X86 | Arch::X86_64) {
+        config
+            .make
+            .require_kconfig("CMDLINE_BOOL".to_string(), KconfigValue::Yes, source);
+    }
+    let cmdline = kernel_cmdline(config);
+    config
+        .make
+        .require_kconfig("CMDLINE".to_string(), KconfigValue::String(cmdline), source);
+
+    Ok(())
+}
+
+/// How a test run in qemu ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// qemu exited with the given non zero exit code.
+    Failed(i32),
+    Timeout,
+    /// qemu did not exit on its own, e.g. it was killed by a signal.
+    Crashed,
+    /// The guest kernel panicked.
+    Panic,
+}
+
+impl Outcome {
+    /// Failures of the test infrastructure rather than of the test itself.
+    pub fn is_infra_failure(&self) -> bool {
+        matches!(self, Self::Timeout | Self::Crashed)
+    }
+
+    pub fn into_result(self) -> Result {
+        match self {
+            Self::Passed => Ok(()),
+            // qemu's own exit code could collide with the ones below
+            Self::Failed(code) => {
+                Err(Error::new(format!("Test failed, qemu exited with {code}")).set_exit_code(1))
+            }
+            Self::Timeout => Err(Error::new("Test timed out").set_exit_code(124)),
+            Self::Crashed => Err(Error::new("qemu crashed").set_exit_code(125)),
+            Self::Panic => Err(Error::new("Kernel panic").set_exit_code(126)),
+        }
+    }
+}
+
+impl core::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Passed => write!(f, "passed"),
+            Self::Failed(code) => write!(f, "failed ({code})"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Crashed => write!(f, "qemu crashed"),
+            Self::Panic => write!(f, "kernel panic"),
         }
     }
 }
@@ -85,10 +474,8 @@ impl QemuCmd {
 pub fn maxmem() -> String {
     let maxmem = procfs::Meminfo::new()
         .map(|m| m.mem_total)
-        .unwrap_or(1024 * 1024 * 1024);
-    let maxmem = "3G";
-    println!("todo");
-    "3G".to_string()
+        .unwrap_or(3 * 1024 * 1024 * 1024);
+    format!("{}M", maxmem / (1024 * 1024))
 }
 
 #[cfg(target_os = "macos")]
@@ -97,9 +484,12 @@ pub fn maxmem() -> String {
     "3G".to_string()
 }
 
-pub fn get_test_deps(_config: &Config, test: impl AsRef<std::ffi::OsStr>) -> Result<String> {
+pub fn get_test_deps(_config: &Config, test: impl AsRef<std::path::Path>) -> Result<TestDeps> {
+    let test = test.as_ref();
     let mut cmd = Command::new(test);
-    cmd.arg("deps").env("KTEST_TEST_LIB", "./lib/testlib.sh");
+    cmd.arg("deps")
+        .arg("json")
+        .env("KTEST_TEST_LIB", "./lib/testlib.sh");
 
     debug!(
         "Running {} {}",
@@ -119,55 +509,52 @@ pub fn get_test_deps(_config: &Config, test: impl AsRef<std::ffi::OsStr>) -> Res
         return Err(Error::new("Failed to run test in dep mode").set_exit_code(out.status.code()));
     }
 
-    let stdout = String::from_utf8(out.stdout).map_err(|e| Error::new(e.to_string()))?;
-    Ok(stdout)
+    let stdout = core::str::from_utf8(&out.stdout)
+        .context(format!("Invalid deps output from {}", test.display()))?;
+    TestDeps::parse(test, stdout)
 }
 
-pub fn update_config_for_test(config: &mut Config, test: impl AsRef<std::ffi::OsStr>) -> Result {
-    let string = get_test_deps(config, test)?;
+pub fn update_config_for_test(config: &mut Config, test: impl AsRef<std::path::Path>) -> Result {
+    let test = test.as_ref();
+    let deps = get_test_deps(config, test)?;
 
-    for line in string.lines() {
-        let line = line.splitn(2, '=').collect::<Vec<_>>();
-        let key = line[0];
-        let value = line[1];
-
-        debug!(key, value, "Parsing value");
-
-        match key {
-            "ktest_arch" => config.make.arch = Some(value.parse()?),
-            "ktest_cpus" => config.qemu.cpus = value.parse()?,
-            "ktest_mem" => config.qemu.mem = value.to_string(),
-            "ktest_kernel_append" => config
-                .qemu
-                .extra_kernel_args
-                .extend(parse_array::<String>(value)?),
-            "ktest_kernel_make_append" => config
-                .make
-                .extra_make_args
-                .extend(parse_array::<String>(value)?),
-            "ktest_kernel_config_require" => {
-                let cfg = parse_array::<String>(value)?;
-                for cfg in &cfg {
-                    let (key, value) = crate::kconfig::parse(cfg)?;
-                    config.make.kconfig.insert(key, value);
-                }
-            }
-            _ => warn!(key, value, "Unknown key"),
-        }
+    if let Some(arch) = &deps.arch {
+        config.make.arch = Some(arch.parse()?);
     }
-
-    Ok(())
-}
-
-fn parse_array<T>(value: &str) -> Result<Vec<T>>
-where
-    T: FromStr,
-    Error: From<<T as FromStr>::Err>,
-{
-    let mut out = Vec::new();
-    let value = value.trim_start_matches('(').trim_end_matches(')');
-    for item in value.split(' ') {
-        out.push(item.parse()?);
+    if let Some(cpus) = deps.cpus {
+        config.qemu.cpus = cpus;
+    }
+    if let Some(mem) = &deps.mem {
+        config.qemu.mem = mem.clone();
+    }
+    if let Some(timeout) = deps.timeout.filter(|t| *t > 0) {
+        config.qemu.timeout = Some(timeout);
     }
-    Ok(out)
+    if let Some(storage_bus) = &deps.storage_bus {
+        config.qemu.storage_bus = storage_bus.clone();
+    }
+    config
+        .qemu
+        .extra_args
+        .extend(deps.qemu_append.iter().cloned());
+    config
+        .qemu
+        .extra_kernel_args
+        .extend(deps.kernel_append.iter().cloned());
+    config
+        .make
+        .extra_make_args
+        .extend(deps.kernel_make_append.iter().cloned());
+    config
+        .make
+        .make_install
+        .extend(deps.make_install.iter().cloned());
+    for cfg in &deps.kernel_config_require {
+        let (key, value) = crate::kconfig::parse(cfg)?;
+        config
+            .make
+            .require_kconfig(key, value, test.display().to_string());
+    }
+
+    update_config_for_qemu(config)
 }
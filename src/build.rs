@@ -1,8 +1,9 @@
 use crate::config::{Arch, Config};
 use crate::make::MakeCmd;
-use crate::{Context, Result};
+use crate::{Context, Error, Result};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use std::process::Command;
 use tracing::*;
 
 #[instrument(name = "build", level = "debug", skip(config))]
@@ -11,16 +12,20 @@ where
     I: IntoIterator<Item = S> + core::fmt::Debug + Clone,
     S: AsRef<std::ffi::OsStr>,
 {
-    // TODO: backup old config if precise
+    // an old manifest must not vouch for a partial install of this build
+    let manifest = crate::manifest::path(config);
+    if manifest.exists() {
+        std::fs::remove_file(&manifest).context("Failed to remove old build manifest")?;
+    }
 
     let config_file = crate::kconfig::new_config(config, args.clone())?;
 
     // run olddefconfig
-    MakeCmd::new(config, Some("olddefconfig"), args.clone())?.run()?;
+    MakeCmd::new(config, Some("olddefconfig"), args.clone())?.run_logged(config)?;
 
     crate::kconfig::check_configs(config, &config_file)?;
 
-    MakeCmd::new(config, config.make.make_arch_target(), args.clone())?.run()?;
+    MakeCmd::new(config, config.make.make_arch_target(), args.clone())?.run_logged(config)?;
 
     let mut boot = config.make.make_build_dir();
     boot.push("arch");
@@ -35,11 +40,36 @@ where
         Arch::Aarch64 => {
             install_file(boot.join("Image"), out.join("vmlinuz"))?;
         }
-        Arch::Mips => {
+        Arch::Mips | Arch::Mips64 | Arch::Mipsel | Arch::Mips64el => {
             install_file(boot.join("vmlinux.strip"), out.join("vmlinuz"))?;
         }
-        _ => {
-            todo!();
+        Arch::PowerPC64le => {
+            // pseries loads the ELF directly
+            install_file(
+                config.make.make_build_dir().join("vmlinux"),
+                out.join("vmlinuz"),
+            )?;
+        }
+        Arch::Riscv64 => {
+            install_file(boot.join("Image"), out.join("vmlinuz"))?;
+        }
+        Arch::Arm => {
+            install_file(boot.join("zImage"), out.join("vmlinuz"))?;
+        }
+        Arch::S390x => {
+            install_file(boot.join("bzImage"), out.join("vmlinuz"))?;
+        }
+        Arch::Loongarch64 => {
+            // the virt machine loads the ELF directly, no EFI stub needed
+            install_file(
+                config.make.make_build_dir().join("vmlinux"),
+                out.join("vmlinuz"),
+            )?;
+        }
+        arch => {
+            return Err(Error::new(format!(
+                "Installing kernels for {arch} is not supported"
+            )));
         }
     }
 
@@ -52,15 +82,31 @@ where
         out.join("config"),
     )?;
 
-    // if there weren't actually any modules selected
-    drop(std::fs::File::create(
-        config.make.make_build_dir().join("modules.order"),
-    ));
-    drop(std::fs::File::create(
-        config.make.make_build_dir().join("modules.builtin"),
-    ));
+    let arch = config.make.arch.context("Arch missing")?;
+    match config.qemu.dtb(&arch) {
+        Some(dtb) => install_dtb(config, dtb, args.clone())?,
+        None if out.join("dtb").exists() => {
+            std::fs::remove_file(out.join("dtb")).context("Failed to remove old dtb")?;
+        }
+        None => {}
+    }
 
-    drop(MakeCmd::new(config, Some("modules_install"), args.clone())?.run());
+    // if there weren't actually any modules selected, without truncating the
+    // lists modules_install needs when there were
+    for list in ["modules.order", "modules.builtin"] {
+        drop(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(config.make.make_build_dir().join(list)),
+        );
+    }
+
+    drop(MakeCmd::new(config, Some("modules_install"), args.clone())?.run_logged(config));
+
+    for dir in &config.make.make_install {
+        make_install(config, dir)?;
+    }
 
     let version = std::fs::read_to_string(
         config
@@ -72,10 +118,179 @@ where
     .context("Failed to read kernel release")?;
     info!("Installed kernel version {}", version);
 
-    // TODO: depmod?
+    let source = crate::source::SourceInfo::collect(config, &version);
+    debug!("Kernel source: {source}");
+    source.write(config)?;
+
+    depmod(config, &version)?;
+
+    crate::kconfig_history::snapshot(config, &version)?;
+
+    let make_args = config
+        .make
+        .extra_make_args
+        .iter()
+        .cloned()
+        .chain(
+            args.into_iter()
+                .map(|a| a.as_ref().to_string_lossy().into_owned()),
+        )
+        .collect();
+    crate::manifest::Manifest::collect(config, &version, make_args)?.write(config)?;
+
     Ok(version)
 }
 
+/// Install the device tree `dtb` as `dtb` in the kernel bin dir.
+///
+/// `.dts` files are preprocessed like the ones in the tree, so they can use
+/// the kernel's `dt-bindings` headers, and compiled with the kernel's dtc.
+/// Anything else names a dtb built by `make dtbs`.
+#[instrument(level = "debug", skip(config, args))]
+fn install_dtb<I, S>(config: &Config, dtb: &Path, args: I) -> Result
+where
+    I: IntoIterator<Item = S> + core::fmt::Debug,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let build_dir = config.make.make_build_dir();
+    let out = config.make.kernel_bin_dir().join("dtb");
+
+    if dtb.extension().and_then(|e| e.to_str()) != Some("dts") {
+        MakeCmd::new(config, Some("dtbs"), args)?.run_logged(config)?;
+
+        let mut src = build_dir.join("arch");
+        src.push(config.make.arch.context("Arch missing")?.kernel_arch());
+        src.push("boot/dts");
+        return install_file(src.join(dtb), out);
+    }
+
+    MakeCmd::new(config, Some("scripts_dtc"), args)?.run_logged(config)?;
+
+    let kernel_dir = Path::new(&config.make.kernel_dir);
+    let preprocessed = build_dir.join("ktest.dts.tmp");
+    let status = Command::new("cpp")
+        .args([
+            "-nostdinc",
+            "-undef",
+            "-D__DTS__",
+            "-x",
+            "assembler-with-cpp",
+            "-P",
+        ])
+        .arg("-I")
+        .arg(dtb.parent().unwrap_or(Path::new(".")))
+        .arg("-I")
+        .arg(kernel_dir.join("include"))
+        .arg("-I")
+        .arg(kernel_dir.join("scripts/dtc/include-prefixes"))
+        .arg("-o")
+        .arg(&preprocessed)
+        .arg(dtb)
+        .status()
+        .context("Failed to execute cpp")?;
+    if !status.success() {
+        return Err(
+            Error::new(format!("Failed to preprocess {}", dtb.display()))
+                .set_exit_code(status.code()),
+        );
+    }
+
+    debug!("Compiling {}", dtb.display());
+    let status = Command::new(build_dir.join("scripts/dtc/dtc"))
+        .args(["-I", "dts", "-O", "dtb", "-o"])
+        .arg(&out)
+        .arg(&preprocessed)
+        .status()
+        .context("Failed to execute dtc")?;
+    drop(std::fs::remove_file(&preprocessed));
+    if !status.success() {
+        return Err(
+            Error::new(format!("Failed to compile {}", dtb.display())).set_exit_code(status.code())
+        );
+    }
+
+    Ok(())
+}
+
+/// Build and install an out-of-tree dependency from `ktest_make_install`.
+///
+/// Directories with a `Kbuild` file, or a Makefile using `obj-m`, are built as
+/// kernel modules against the fresh kernel build. Everything else is treated as
+/// a userspace tool and installed with `DESTDIR` pointing at the kernel bin dir.
+#[instrument(level = "debug", skip(config))]
+fn make_install(config: &Config, dir: &Path) -> Result {
+    let dir = dir
+        .canonicalize()
+        .context(format!("Failed to resolve {}", dir.display()))?;
+
+    if is_kernel_module(&dir) {
+        info!("Building out-of-tree module {}", dir.display());
+        let m = format!("M={}", dir.to_str().context("Invalid module path")?);
+        MakeCmd::new(config, Some("modules"), [m.as_str()])?.run_logged(config)?;
+        MakeCmd::new(config, Some("modules_install"), [m.as_str()])?.run_logged(config)?;
+    } else {
+        info!("Building {}", dir.display());
+        MakeCmd::new_in(config, &dir, None, [""; 0])?.run_logged(config)?;
+
+        let destdir = format!(
+            "DESTDIR={}",
+            config
+                .make
+                .kernel_bin_dir()
+                .canonicalize()?
+                .to_str()
+                .context("Invalid kernel bin dir")?
+        );
+        MakeCmd::new_in(config, &dir, Some("install"), [destdir.as_str()])?.run_logged(config)?;
+    }
+
+    Ok(())
+}
+
+fn is_kernel_module(dir: &Path) -> bool {
+    if dir.join("Kbuild").exists() {
+        return true;
+    }
+
+    std::fs::read_to_string(dir.join("Makefile"))
+        .map(|m| m.contains("obj-m"))
+        .unwrap_or(false)
+}
+
+/// Generate `modules.dep` and friends for the installed modules of `version`.
+///
+/// Missing symbols and dependency cycles reported by depmod are only logged as
+/// warnings, as a kernel with broken modules can still be booted.
+#[instrument(level = "debug", skip(config))]
+fn depmod(config: &Config, version: &str) -> Result {
+    let base = config.make.kernel_bin_dir();
+    if !base.join("lib/modules").join(version).is_dir() {
+        debug!("No modules installed, skipping depmod");
+        return Ok(());
+    }
+
+    let mut cmd = Command::new(&config.make.depmod);
+    cmd.arg("-b").arg(&base);
+    let system_map = config.make.make_build_dir().join("System.map");
+    if system_map.exists() {
+        cmd.arg("-e").arg("-F").arg(system_map);
+    }
+    cmd.arg(version);
+
+    debug!("Running depmod");
+    let out = cmd.output().context("Failed to execute depmod")?;
+    for line in String::from_utf8_lossy(&out.stderr).lines() {
+        warn!("{}", line);
+    }
+
+    // depmod exits non-zero on dependency cycles, which must not fail the build
+    if !out.status.success() {
+        warn!("depmod failed: {}", out.status);
+    }
+
+    Ok(())
+}
+
 fn install_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result {
     install_file_perm(src, dst, std::fs::Permissions::from_mode(0o644))
 }
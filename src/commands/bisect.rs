@@ -0,0 +1,60 @@
+use crate::bisect::BisectState;
+use crate::config::Config;
+use crate::Result;
+use clap::FromArgMatches;
+use tracing::*;
+
+pub fn command(config: &Config) -> clap::Command {
+    let cmd = clap::Command::new("bisect")
+        .about("Find the commit that broke a test with git bisect")
+        .arg(
+            clap::Arg::new("make-args")
+                .long("args")
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            clap::Arg::new("reset")
+                .long("reset")
+                .help("Restart a bisect already in progress")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("good")
+                .required(true)
+                .value_parser(clap::value_parser!(String))
+                .index(1),
+        )
+        .arg(
+            clap::Arg::new("bad")
+                .required(true)
+                .value_parser(clap::value_parser!(String))
+                .index(2),
+        )
+        .arg(
+            clap::Arg::new("test")
+                .required(true)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .value_hint(clap::ValueHint::ExecutablePath)
+                .index(3),
+        );
+    config.qemu.augument_args(cmd)
+}
+
+#[instrument(name = "bisect", level = "debug", skip(config, matches))]
+pub fn run(config: &mut Config, matches: &clap::ArgMatches) -> Result {
+    config.qemu.update_from_arg_matches(matches)?;
+
+    let test = matches.get_one::<std::path::PathBuf>("test").unwrap();
+    crate::boot::update_config_for_test(config, test)?;
+
+    let state = BisectState {
+        good: matches.get_one::<String>("good").unwrap().clone(),
+        bad: matches.get_one::<String>("bad").unwrap().clone(),
+        // the test is run from the kernel tree while bisecting
+        test: test.canonicalize()?,
+    };
+    let args = matches.get_many::<String>("make-args").unwrap_or_default();
+
+    crate::bisect::bisect(config, &state, matches.get_flag("reset"), args)
+}
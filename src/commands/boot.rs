@@ -13,10 +13,31 @@ pub fn command(config: &Config) -> clap::Command {
                 .action(clap::ArgAction::Append)
                 .value_parser(clap::value_parser!(String)),
         )
+        .arg(
+            clap::Arg::new("ref")
+                .long("ref")
+                .value_name("COMMIT")
+                .help("Build this git ref in a worktree under the out dir")
+                .value_parser(clap::value_parser!(String)),
+        )
         .arg(
             clap::Arg::new("no-build")
                 .long("no-build")
                 .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("kernel-archive")
+                .long("kernel-archive")
+                .value_name("ARCHIVE")
+                .help("Boot a kernel exported with `ktest export` instead of building")
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .conflicts_with_all(["ref", "no-build", "make-args"]),
+        )
+        .arg(
+            clap::Arg::new("snapshot-after-boot")
+                .long("snapshot-after-boot")
+                .help("Save the VM once the guest is ready, for `ktest run --from-snapshot`")
+                .action(clap::ArgAction::SetTrue),
         );
     config.qemu.augument_args(cmd)
 }
@@ -25,12 +46,35 @@ pub fn command(config: &Config) -> clap::Command {
 pub fn run(config: &mut Config, matches: &clap::ArgMatches) -> Result {
     config.qemu.update_from_arg_matches(matches)?;
 
-    if !matches.get_flag("no-build") {
-        let args = matches.get_many::<String>("make-args").unwrap_or_default();
-        crate::build::build(config, args)?;
+    // importing switches to the arch of the archive, which everything qemu
+    // related below depends on
+    let archive = matches.get_one::<std::path::PathBuf>("kernel-archive");
+    if let Some(archive) = archive {
+        crate::archive::import(config, archive)?;
+    }
+    crate::boot::update_config_for_qemu(config)?;
+
+    if let Some(git_ref) = matches.get_one::<String>("ref") {
+        crate::worktree::use_ref(config, git_ref)?;
+    }
+
+    if archive.is_none() {
+        if !matches.get_flag("no-build") {
+            let args = matches.get_many::<String>("make-args").unwrap_or_default();
+            crate::build::build(config, args)?;
+        } else {
+            crate::manifest::verify(config)?;
+        }
     }
 
     trace!("using qemu: {:?}", config.qemu_path());
+    if let Some(source) = crate::source::SourceInfo::read(config)? {
+        println!("Booting kernel {source}");
+    }
+
+    if matches.get_flag("snapshot-after-boot") {
+        return crate::snapshot::snapshot_after_boot(config);
+    }
 
     //crate::boot::boot(config, args)?;
     QemuCmd::new(config)?.run()?;
@@ -1,21 +1,111 @@
 use crate::config::Config;
-use crate::Result;
+use crate::make::MakeCmd;
+use crate::warnings;
+use crate::{Error, Result};
+use std::path::{Path, PathBuf};
 use tracing::*;
 
 pub fn command(_config: &Config) -> clap::Command {
-    clap::Command::new("build").about("Build the kernel").arg(
-        clap::Arg::new("make-args")
-            .long("args")
-            .action(clap::ArgAction::Append)
-            .value_parser(clap::value_parser!(String)),
-    )
+    clap::Command::new("build")
+        .about("Build the kernel")
+        .arg(
+            clap::Arg::new("make-args")
+                .long("args")
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            clap::Arg::new("ref")
+                .long("ref")
+                .value_name("COMMIT")
+                .help("Build this git ref in a worktree under the out dir")
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            clap::Arg::new("warnings-baseline")
+                .long("warnings-baseline")
+                .value_name("FILE")
+                .help("Fail the build on warnings not listed in this file, rebuilds everything")
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(clap::ValueHint::FilePath),
+        )
+        .arg(
+            clap::Arg::new("update-baseline")
+                .long("update-baseline")
+                .help("Record the warnings of this build as the new baseline")
+                .requires("warnings-baseline")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("extra-warnings")
+                .long("extra-warnings")
+                .help("Build with W=1")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("sparse")
+                .long("sparse")
+                .help("Run sparse on compiled files, C=1")
+                .action(clap::ArgAction::SetTrue),
+        )
 }
 
 #[instrument(name = "build", level = "debug", skip(config, matches))]
-pub fn run(config: &Config, matches: &clap::ArgMatches) -> Result {
+pub fn run(config: &mut Config, matches: &clap::ArgMatches) -> Result {
+    if let Some(git_ref) = matches.get_one::<String>("ref") {
+        crate::worktree::use_ref(config, git_ref)?;
+    }
+
+    if matches.get_flag("extra-warnings") {
+        config.make.extra_make_args.push("W=1".to_string());
+    }
+    if matches.get_flag("sparse") {
+        config.make.extra_make_args.push("C=1".to_string());
+    }
+
     let args = matches.get_many::<String>("make-args").unwrap_or_default();
+    let baseline = matches.get_one::<PathBuf>("warnings-baseline");
 
+    // diagnostics only come from files make compiles, so an incremental build
+    // would compare a partial set of warnings
+    if baseline.is_some() {
+        MakeCmd::new(config, Some("clean"), args.clone())?.run_logged(config)?;
+    }
+
+    // warnings of earlier make runs in this process are not ours
+    drop(crate::make::take_diagnostics());
     crate::build::build(config, args)?;
 
+    if let Some(baseline) = baseline {
+        check_warnings(baseline, matches.get_flag("update-baseline"))?;
+    }
+
     Ok(())
 }
+
+fn check_warnings(baseline: &Path, update: bool) -> Result {
+    let current = warnings::warnings(&crate::make::take_diagnostics());
+    if update {
+        return warnings::write_baseline(baseline, &current);
+    }
+
+    let known = warnings::read_baseline(baseline)?;
+    let new = warnings::new_warnings(&current, &known);
+    if new.is_empty() {
+        info!("No new warnings");
+        return Ok(());
+    }
+
+    eprintln!(
+        "{} new warning(s) compared to {}:",
+        new.len(),
+        baseline.display()
+    );
+    for warning in &new {
+        eprintln!("  {warning}");
+    }
+    Err(Error::new(format!(
+        "Build added {} new warning(s)",
+        new.len()
+    )))
+}
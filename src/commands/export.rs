@@ -0,0 +1,31 @@
+use crate::config::Config;
+use crate::Result;
+use std::path::PathBuf;
+use tracing::*;
+
+pub fn command(_config: &Config) -> clap::Command {
+    clap::Command::new("export")
+        .about("Bundle the built kernel into a compressed tarball")
+        .arg(
+            clap::Arg::new("strip-debug")
+                .long("strip-debug")
+                .help("Strip debug info from vmlinux")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("archive")
+                .required(true)
+                .value_name("ARCHIVE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(clap::ValueHint::FilePath)
+                .index(1),
+        )
+}
+
+#[instrument(name = "export", level = "debug", skip(config, matches))]
+pub fn run(config: &Config, matches: &clap::ArgMatches) -> Result {
+    let archive = matches.get_one::<PathBuf>("archive").unwrap();
+    crate::archive::export(config, archive, matches.get_flag("strip-debug"))?;
+    println!("Exported kernel to {}", archive.display());
+    Ok(())
+}
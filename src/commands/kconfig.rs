@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::kconfig_history;
+use crate::{Context, Result};
+use clap::{Arg, Command};
+use tracing::*;
+
+pub fn command(_config: &Config) -> Command {
+    Command::new("kconfig")
+        .about("Inspect the history of built kernel configs")
+        .subcommand_required(true)
+        .subcommand(Command::new("log").about("List saved kernel configs"))
+        .subcommand(
+            Command::new("diff")
+                .about("Show symbol level differences between two saved configs")
+                .arg(
+                    Arg::new("a")
+                        .value_name("OLD")
+                        .help("Snapshot index, name or path [default: -2]")
+                        .allow_negative_numbers(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("b")
+                        .value_name("NEW")
+                        .help("Snapshot index, name or path [default: -1]")
+                        .allow_negative_numbers(true)
+                        .index(2),
+                ),
+        )
+}
+
+#[instrument(name = "kconfig", level = "debug", skip(config, matches))]
+pub fn run(config: &Config, matches: &clap::ArgMatches) -> Result {
+    let snapshots = kconfig_history::snapshots(config)?;
+
+    match matches.subcommand().context("No subcomand provided")? {
+        ("log", _) => {
+            for (idx, snapshot) in snapshots.iter().enumerate() {
+                println!("{idx:>4}  {}  {}", snapshot.timestamp, snapshot.release);
+            }
+        }
+        ("diff", matches) => {
+            let spec = |name, default| {
+                matches
+                    .get_one::<String>(name)
+                    .map(String::as_str)
+                    .unwrap_or(default)
+            };
+            let a = kconfig_history::resolve(&snapshots, spec("a", "-2"))?;
+            let b = kconfig_history::resolve(&snapshots, spec("b", "-1"))?;
+            debug!("Comparing {} to {}", a.display(), b.display());
+
+            for change in kconfig_history::diff(&a, &b)? {
+                println!("{change}");
+            }
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
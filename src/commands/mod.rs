@@ -1,6 +1,10 @@
+pub mod bisect;
 pub mod boot;
 pub mod build;
 pub mod config;
+pub mod export;
+pub mod kconfig;
 pub mod make;
 pub mod oldconfig;
 pub mod run;
+pub mod ssh;
@@ -1,5 +1,6 @@
 use crate::boot::QemuCmd;
 use crate::config::Config;
+use crate::source::SourceInfo;
 use crate::Result;
 use clap::FromArgMatches;
 use tracing::*;
@@ -13,11 +14,24 @@ pub fn command(config: &Config) -> clap::Command {
                 .action(clap::ArgAction::Append)
                 .value_parser(clap::value_parser!(String)),
         )
+        .arg(
+            clap::Arg::new("ref")
+                .long("ref")
+                .value_name("COMMIT")
+                .help("Build this git ref in a worktree under the out dir")
+                .value_parser(clap::value_parser!(String)),
+        )
         .arg(
             clap::Arg::new("no-build")
                 .long("no-build")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("from-snapshot")
+                .long("from-snapshot")
+                .help("Restore the VM saved by --snapshot-after-boot, implies --no-build")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             clap::Arg::new("parse-only")
                 .long("parse-only")
@@ -46,12 +60,32 @@ pub fn run(config: &mut Config, matches: &clap::ArgMatches) -> Result {
         return Ok(());
     }
 
-    if !matches.get_flag("no-build") {
+    if let Some(git_ref) = matches.get_one::<String>("ref") {
+        crate::worktree::use_ref(config, git_ref)?;
+    }
+
+    if !matches.get_flag("no-build") && !matches.get_flag("from-snapshot") {
         let args = matches.get_many::<String>("make-args").unwrap_or_default();
         crate::build::build(config, args)?;
+    } else {
+        crate::manifest::verify(config)?;
+    }
+
+    let source = SourceInfo::read(config)?;
+    if let Some(source) = &source {
+        println!("Testing kernel {source}");
     }
 
-    QemuCmd::new(config)?.run()?;
+    let outcome = if matches.get_flag("from-snapshot") {
+        crate::snapshot::run_from_snapshot(config, std::path::Path::new(test))?
+    } else {
+        QemuCmd::new(config)?.run_test(config.qemu.timeout())?
+    };
 
-    Ok(())
+    outcome.into_result().map_err(|mut e| {
+        if let Some(source) = &source {
+            e.context = format!("{} on kernel {source}", e.context);
+        }
+        e
+    })
 }
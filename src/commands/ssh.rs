@@ -0,0 +1,25 @@
+use crate::config::Config;
+use crate::Result;
+use tracing::*;
+
+pub fn command(_config: &Config) -> clap::Command {
+    clap::Command::new("ssh")
+        .about("Connect to the running VM with ssh, see --network")
+        .arg(
+            clap::Arg::new("command")
+                .value_name("COMMAND")
+                .help("Command to run instead of a shell")
+                .num_args(1..)
+                .last(true),
+        )
+}
+
+#[instrument(name = "ssh", level = "debug", skip(config, matches))]
+pub fn run(config: &Config, matches: &clap::ArgMatches) -> Result {
+    let args = matches
+        .get_many::<String>("command")
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    crate::ssh::ssh(config, &args)
+}
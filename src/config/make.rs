@@ -1,3 +1,4 @@
+use crate::kconfig::{BaseConfig, KconfigValue};
 use crate::{Context, Error, Result};
 use clap::builder::PossibleValue;
 use clap::{
@@ -6,7 +7,7 @@ use clap::{
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use tracing::trace;
+use tracing::{debug, trace};
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq, Hash)]
 #[serde(try_from = "String")]
@@ -20,6 +21,13 @@ pub enum Arch {
     Sparc64,
     PowerPC,
     PowerPC64,
+    PowerPC64le,
+    Mipsel,
+    Mips64el,
+    Riscv64,
+    Arm,
+    S390x,
+    Loongarch64,
 }
 
 impl Arch {
@@ -34,15 +42,88 @@ impl Arch {
             Self::Sparc64 => "sparc",
             Self::PowerPC => "powerpc",
             Self::PowerPC64 => "powerpc",
+            Self::PowerPC64le => "powerpc",
+            Self::Mipsel => "mips",
+            Self::Mips64el => "mips",
+            Self::Riscv64 => "riscv",
+            Self::Arm => "arm",
+            Self::S390x => "s390",
+            Self::Loongarch64 => "loongarch",
         }
     }
 
     pub fn make_target(&self) -> Option<&'static str> {
         match self {
-            Self::Mips => Some("vmlinuz"),
+            Self::Mips | Self::Mips64 | Self::Mipsel | Self::Mips64el => Some("vmlinuz"),
             _ => None,
         }
     }
+
+    /// Name of the default boot loader on removable media, for UEFI boots.
+    ///
+    /// Only arches whose installed kernel image has an EFI stub.
+    pub fn efi_boot_file(&self) -> Option<&'static str> {
+        match self {
+            Self::X86 => Some("BOOTIA32.EFI"),
+            Self::X86_64 => Some("BOOTX64.EFI"),
+            Self::Aarch64 => Some("BOOTAA64.EFI"),
+            Self::Arm => Some("BOOTARM.EFI"),
+            Self::Riscv64 => Some("BOOTRISCV64.EFI"),
+            _ => None,
+        }
+    }
+
+    /// Device reporting guest panics to qemu, on machines that need one.
+    pub fn pvpanic_device(&self) -> Option<&'static str> {
+        match self {
+            Self::X86 | Self::X86_64 => Some("pvpanic"),
+            Self::Aarch64 | Self::Arm | Self::Riscv64 | Self::Loongarch64 => Some("pvpanic-pci"),
+            _ => None,
+        }
+    }
+
+    /// Whether the machine qemu boots for this arch has a fw_cfg device.
+    pub fn has_fw_cfg(&self) -> bool {
+        // malta, pseries and s390-ccw-virtio
+        !matches!(
+            self,
+            Self::Mips
+                | Self::Mips64
+                | Self::Mipsel
+                | Self::Mips64el
+                | Self::PowerPC64
+                | Self::PowerPC64le
+                | Self::S390x
+        )
+    }
+
+    /// Whether this is the big endian flavour of a bi-endian architecture.
+    pub fn big_endian(&self) -> Option<bool> {
+        match self {
+            Self::Mips | Self::Mips64 | Self::PowerPC64 => Some(true),
+            Self::Mipsel | Self::Mips64el | Self::PowerPC64le => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Kconfig symbols any kernel for this architecture needs.
+    pub fn kconfig(&self) -> Vec<(&'static str, KconfigValue)> {
+        let Some(big_endian) = self.big_endian() else {
+            return Vec::new();
+        };
+        let yes_no = |v| {
+            if v {
+                KconfigValue::Yes
+            } else {
+                KconfigValue::No
+            }
+        };
+
+        vec![
+            ("CPU_BIG_ENDIAN", yes_no(big_endian)),
+            ("CPU_LITTLE_ENDIAN", yes_no(!big_endian)),
+        ]
+    }
 }
 
 impl core::fmt::Display for Arch {
@@ -87,6 +168,13 @@ impl ValueEnum for Arch {
             Self::Sparc64,
             Self::PowerPC,
             Self::PowerPC64,
+            Self::PowerPC64le,
+            Self::Mipsel,
+            Self::Mips64el,
+            Self::Riscv64,
+            Self::Arm,
+            Self::S390x,
+            Self::Loongarch64,
         ]
     }
 
@@ -101,6 +189,59 @@ impl ValueEnum for Arch {
             Self::Sparc64 => PossibleValue::new("sparc64"),
             Self::PowerPC => PossibleValue::new("powerpc").alias("ppc"),
             Self::PowerPC64 => PossibleValue::new("powerpc64").alias("ppc64"),
+            Self::PowerPC64le => PossibleValue::new("powerpc64le").alias("ppc64le"),
+            Self::Mipsel => PossibleValue::new("mipsel"),
+            Self::Mips64el => PossibleValue::new("mips64el"),
+            Self::Riscv64 => PossibleValue::new("riscv64").alias("riscv"),
+            Self::Arm => PossibleValue::new("arm").aliases(["armv7l", "armv8l", "armhf"]),
+            Self::S390x => PossibleValue::new("s390x").alias("s390"),
+            Self::Loongarch64 => PossibleValue::new("loongarch64").alias("loongarch"),
+        })
+    }
+}
+
+/// What to do when the generated config does not contain a requested symbol.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KconfigCheck {
+    #[default]
+    Error,
+    Warn,
+}
+
+impl ValueEnum for KconfigCheck {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Error, Self::Warn]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Error => PossibleValue::new("error"),
+            Self::Warn => PossibleValue::new("warn"),
+        })
+    }
+}
+
+/// How compiler diagnostics from kernel builds are reported.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsFormat {
+    /// A short list of errors when the build fails.
+    #[default]
+    Summary,
+    /// Every diagnostic as a json object per line on stdout.
+    Json,
+}
+
+impl ValueEnum for DiagnosticsFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Summary, Self::Json]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Summary => PossibleValue::new("summary"),
+            Self::Json => PossibleValue::new("json"),
         })
     }
 }
@@ -108,12 +249,40 @@ impl ValueEnum for Arch {
 #[derive(Debug, Clone, Deserialize)]
 pub struct Make {
     pub path: String,
+    #[serde(default = "default_depmod")]
+    pub depmod: String,
     pub jobs: Option<usize>,
     pub arch: Option<Arch>,
     pub out_dir: String,
     pub kernel_dir: String,
     pub extra_make_args: Vec<String>,
-    pub kconfig: HashMap<String, String>,
+    /// Out-of-tree modules and tools to build against the kernel.
+    #[serde(default)]
+    pub make_install: Vec<PathBuf>,
+    #[serde(default)]
+    pub base_config: BaseConfig,
+    pub kconfig: HashMap<String, KconfigValue>,
+    /// `.config` style files merged into `kconfig`, in order.
+    #[serde(default)]
+    pub kconfig_fragments: Vec<PathBuf>,
+    /// Where each entry in `kconfig` was requested, for error reporting.
+    #[serde(skip)]
+    pub kconfig_source: HashMap<String, String>,
+    /// Symbols set if the kernel has them, but not checked, see
+    /// [`Make::want_kconfig`].
+    #[serde(skip)]
+    pub optional_kconfig: HashMap<String, KconfigValue>,
+    #[serde(default)]
+    pub kconfig_check: KconfigCheck,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsFormat,
+    /// Name of the managed worktree `kernel_dir` points to, see `--ref`.
+    #[serde(skip)]
+    pub worktree: Option<String>,
+    /// Unpacked kernel archive used instead of the build output, see
+    /// `--kernel-archive`.
+    #[serde(skip)]
+    pub kernel_archive_dir: Option<PathBuf>,
 }
 
 impl Make {
@@ -179,10 +348,57 @@ impl Make {
             Arg::new("make-kconfig")
                 .long("kconfig")
                 .value_name("KCONFIG")
-                .value_parser(value_parser!(String))
+                .value_parser(crate::kconfig::parse)
                 .action(ArgAction::Append)
                 .global(true),
         )
+        .arg(
+            Arg::new("make-kconfig-fragment")
+                .long("kconfig-fragment")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath)
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("make-base-config")
+                .long("base-config")
+                .value_name("BASE")
+                .help("allnoconfig, tinyconfig, defconfig or a path to a .config")
+                .value_parser(|s: &str| s.parse::<BaseConfig>())
+                .value_hint(ValueHint::FilePath)
+                .default_value(self.base_config.to_string())
+                .global(true),
+        )
+        .arg(
+            Arg::new("make-diagnostics")
+                .long("diagnostics")
+                .value_name("FORMAT")
+                .value_parser(clap::builder::EnumValueParser::<DiagnosticsFormat>::new())
+                .default_value(
+                    self.diagnostics
+                        .to_possible_value()
+                        .expect("no values are skipped")
+                        .get_name()
+                        .to_string(),
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::new("make-kconfig-check")
+                .long("kconfig-check")
+                .value_name("MODE")
+                .value_parser(clap::builder::EnumValueParser::<KconfigCheck>::new())
+                .default_value(
+                    self.kconfig_check
+                        .to_possible_value()
+                        .expect("no values are skipped")
+                        .get_name()
+                        .to_string(),
+                )
+                .global(true),
+        )
         .group(
             clap::ArgGroup::new("make-args")
                 .args([
@@ -196,15 +412,77 @@ impl Make {
         )
     }
 
+    /// Request `key` to be set to `value`, remembering who asked for it.
+    pub fn require_kconfig(&mut self, key: String, value: KconfigValue, source: impl Into<String>) {
+        self.kconfig_source.insert(key.clone(), source.into());
+        self.kconfig.insert(key, value);
+    }
+
+    /// Set `key` for features ktest can do without, e.g. symbols older
+    /// kernels don't have. Unlike required symbols these are not checked.
+    pub fn want_kconfig(&mut self, key: &str, value: KconfigValue) {
+        self.optional_kconfig.insert(key.to_string(), value);
+    }
+
+    /// Merge a kconfig fragment into `kconfig`, reporting values it redefines
+    /// the way `scripts/kconfig/merge_config.sh` does.
+    pub fn merge_kconfig_fragment(&mut self, file: &Path) -> Result {
+        debug!("Merging kconfig fragment {}", file.display());
+        for (key, value) in crate::kconfig::read_fragment(file)? {
+            if let Some(prev) = self.kconfig.get(&key).filter(|prev| **prev != value) {
+                println!(
+                    "Value of CONFIG_{key} is redefined by fragment {}:",
+                    file.display()
+                );
+                println!(
+                    "Previous value: {prev} (from {})",
+                    self.kconfig_source(&key)
+                );
+                println!("New value:      {value}");
+                println!();
+            }
+            self.require_kconfig(key, value, file.display().to_string());
+        }
+        Ok(())
+    }
+
+    /// All requested symbols: `kconfig` on top of what the arch requires.
+    pub fn required_kconfig(&self) -> HashMap<String, KconfigValue> {
+        let mut ret: HashMap<String, KconfigValue> = self
+            .arch
+            .map(|a| a.kconfig())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        ret.extend(self.kconfig.clone());
+        ret
+    }
+
+    /// Describe where the requirement for `key` came from.
+    pub fn kconfig_source(&self, key: &str) -> String {
+        if let Some(source) = self.kconfig_source.get(key) {
+            return source.clone();
+        }
+        match self.arch {
+            Some(arch) if !self.kconfig.contains_key(key) => format!("arch {arch}"),
+            _ => "config file".to_string(),
+        }
+    }
+
     pub fn out_dir(&self) -> &Path {
         Path::new(&self.out_dir)
     }
 
     pub fn kernel_bin_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.kernel_archive_dir {
+            return dir.clone();
+        }
         let mut path = PathBuf::from(&self.out_dir);
         path.push(format!(
-            "kernel.{}",
-            self.arch.as_ref().unwrap().kernel_arch()
+            "kernel.{}{}",
+            self.arch.as_ref().unwrap(),
+            self.worktree_suffix()
         ));
         path
     }
@@ -212,14 +490,29 @@ impl Make {
     pub fn make_build_dir(&self) -> PathBuf {
         let mut path = PathBuf::from(&self.out_dir);
         path.push(format!(
-            "kernel_build.{}",
-            self.arch.as_ref().unwrap().kernel_arch()
+            "kernel_build.{}{}",
+            self.arch.as_ref().unwrap(),
+            self.worktree_suffix()
         ));
         path
     }
 
-    pub fn make_build_dir_arg(&self) -> String {
-        format!("O={}", self.make_build_dir().to_str().unwrap())
+    fn worktree_suffix(&self) -> String {
+        self.worktree
+            .as_ref()
+            .map(|w| format!(".{w}"))
+            .unwrap_or_default()
+    }
+
+    /// `O=` for the build dir. Absolute, since make runs in the kernel source
+    /// dir, which is a worktree for `--ref` builds.
+    pub fn make_build_dir_arg(&self) -> Result<String> {
+        let dir = std::path::absolute(self.make_build_dir())
+            .context("Failed to resolve kernel build dir")?;
+        Ok(format!(
+            "O={}",
+            dir.to_str().context("Invalid kernel build dir")?
+        ))
     }
 
     pub fn make_arch_arg(&self) -> String {
@@ -235,7 +528,7 @@ impl Make {
     }
 
     fn jobs_or_default(matches: &ArgMatches) -> Result<usize> {
-        let jobs = matches.get_one::<usize>("make-jobs").map(|v| *v);
+        let jobs = matches.get_one::<usize>("make-jobs").copied();
         if let Some(jobs) = jobs {
             Ok(jobs)
         } else {
@@ -244,7 +537,7 @@ impl Make {
     }
 
     fn arch_or_default(matches: &ArgMatches) -> Result<Arch> {
-        let arch = matches.get_one::<Arch>("make-arch").map(|v| *v);
+        let arch = matches.get_one::<Arch>("make-arch").copied();
         if let Some(arch) = arch {
             Ok(arch)
         } else {
@@ -260,6 +553,7 @@ impl FromArgMatches for Make {
         tracing::warn!("Creating Make config without reading config");
         let ret = Self {
             path: matches.get_one::<String>("make-path").unwrap().clone(),
+            depmod: default_depmod(),
             jobs: Some(Self::jobs_or_default(matches).unwrap()),
             arch: Some(Self::arch_or_default(matches).unwrap()),
             out_dir: matches.get_one::<String>("make-out-dir").unwrap().clone(),
@@ -268,7 +562,23 @@ impl FromArgMatches for Make {
                 .unwrap()
                 .clone(),
             extra_make_args: Vec::new(),
+            make_install: Vec::new(),
+            base_config: matches
+                .get_one::<BaseConfig>("make-base-config")
+                .unwrap()
+                .clone(),
             kconfig: HashMap::new(),
+            kconfig_fragments: Vec::new(),
+            kconfig_source: HashMap::new(),
+            optional_kconfig: HashMap::new(),
+            kconfig_check: *matches
+                .get_one::<KconfigCheck>("make-kconfig-check")
+                .unwrap(),
+            diagnostics: *matches
+                .get_one::<DiagnosticsFormat>("make-diagnostics")
+                .unwrap(),
+            worktree: None,
+            kernel_archive_dir: None,
         };
 
         Ok(ret)
@@ -287,20 +597,47 @@ impl FromArgMatches for Make {
             .unwrap()
             .clone();
 
-        for arg in matches
-            .get_many::<String>("make-kconfig")
+        self.kconfig_fragments.extend(
+            matches
+                .get_many::<PathBuf>("make-kconfig-fragment")
+                .unwrap_or_default()
+                .cloned(),
+        );
+        for fragment in self.kconfig_fragments.clone() {
+            self.merge_kconfig_fragment(&fragment).map_err(|e| {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("{}: {e}\n", fragment.display()),
+                )
+            })?;
+        }
+
+        for (key, value) in matches
+            .get_many::<(String, KconfigValue)>("make-kconfig")
             .unwrap_or_default()
         {
-            // TODO: proper error handling
-            let (key, value) = crate::kconfig::parse(arg).expect("Invalid kconfig argument");
             trace!("inserting cmd line kconfig: {}={}", key, value);
-            self.kconfig.insert(key, value);
+            self.require_kconfig(key.clone(), value.clone(), "--kconfig");
         }
+        self.base_config = matches
+            .get_one::<BaseConfig>("make-base-config")
+            .unwrap()
+            .clone();
+        self.diagnostics = *matches
+            .get_one::<DiagnosticsFormat>("make-diagnostics")
+            .unwrap();
+        self.kconfig_check = *matches
+            .get_one::<KconfigCheck>("make-kconfig-check")
+            .unwrap();
 
         Ok(())
     }
 }
 
+fn default_depmod() -> String {
+    "depmod".to_string()
+}
+
 #[cfg(any(target_os = "android", target_os = "linux"))]
 fn get_nprocs() -> Result<usize> {
     nix::unistd::sysconf(nix::unistd::SysconfVar::_NPROCESSORS_ONLN)
@@ -323,8 +660,72 @@ fn get_nprocs() -> Result<usize> {
 fn get_arch() -> Result<Arch> {
     let uts = nix::sys::utsname::uname().context("Failed to get uname")?;
 
-    uts.machine()
+    let arch = uts
+        .machine()
         .to_str()
         .context("Failed to get machine arch name")?
-        .parse()
+        .parse()?;
+
+    // mips reports the same machine name for both byte orders
+    Ok(match arch {
+        Arch::Mips if cfg!(target_endian = "little") => Arch::Mipsel,
+        Arch::Mips64 if cfg!(target_endian = "little") => Arch::Mips64el,
+        arch => arch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make() -> Make {
+        serde_json::from_value(serde_json::json!({
+            "path": "make",
+            "jobs": null,
+            "arch": null,
+            "out_dir": "ktest-out",
+            "kernel_dir": ".",
+            "extra_make_args": [],
+            "kconfig": { "A": "y", "B": "y" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_fragments() {
+        let dir = std::env::temp_dir().join(format!("ktest-fragments-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.config");
+        let second = dir.join("second.config");
+        std::fs::write(&first, "CONFIG_B=m\nCONFIG_C=y\n").unwrap();
+        std::fs::write(&second, "# CONFIG_C is not set\nCONFIG_D=0x10\n").unwrap();
+
+        let mut make = make();
+        make.merge_kconfig_fragment(&first).unwrap();
+        make.merge_kconfig_fragment(&second).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(make.kconfig["A"], KconfigValue::Yes);
+        assert_eq!(make.kconfig["B"], KconfigValue::Module);
+        assert_eq!(make.kconfig["C"], KconfigValue::No);
+        assert_eq!(make.kconfig["D"], KconfigValue::Hex(0x10));
+        assert_eq!(make.kconfig_source("A"), "config file");
+        assert_eq!(make.kconfig_source("B"), first.display().to_string());
+        assert_eq!(make.kconfig_source("C"), second.display().to_string());
+    }
+
+    #[test]
+    fn build_dir_arg_is_absolute() {
+        let mut make = make();
+        make.arch = Some(Arch::X86_64);
+        make.worktree = Some("v6.8".to_string());
+
+        let dir = std::env::current_dir()
+            .unwrap()
+            .join("ktest-out/kernel_build.x86_64.v6.8");
+        assert_eq!(
+            make.make_build_dir_arg().unwrap(),
+            format!("O={}", dir.display())
+        );
+    }
 }
@@ -1,7 +1,7 @@
 mod make;
-pub use make::{Arch, Make};
+pub use make::{Arch, DiagnosticsFormat, KconfigCheck};
 mod qemu;
-pub use qemu::{Qemu, QemuConfig};
+pub use qemu::{BootMode, Network};
 
 use crate::Result;
 
@@ -15,6 +15,9 @@ use tracing_subscriber::EnvFilter;
 pub struct Config {
     pub qemu: qemu::Qemu,
     pub make: make::Make,
+    /// Number of `-v` flags given on the command line.
+    #[serde(skip)]
+    pub verbose: u8,
 }
 
 impl Config {
@@ -41,6 +44,19 @@ impl Config {
         cfg.try_deserialize()
     }
 
+    /// Only the built in defaults, without config files or the environment.
+    #[cfg(test)]
+    pub fn embedded() -> Self {
+        config::Config::builder()
+            .add_source(File::from_str(
+                Self::EMBED_CONFIG_STR,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .unwrap()
+    }
+
     pub fn init(&self) -> Result {
         // TODO: log init
         let logger = tracing_subscriber::FmtSubscriber::builder()
@@ -55,14 +71,14 @@ impl Config {
     }
 
     pub fn qemu_path(&self) -> Option<&str> {
-        self.make.arch.as_ref().map(|a| self.qemu.path(a)).flatten()
+        self.make.arch.as_ref().and_then(|a| self.qemu.path(a))
     }
 
     pub fn qemu_args(&self) -> impl Iterator<Item = &str> {
-        self.qemu.qemu_args(&self.make.arch.as_ref().unwrap())
+        self.qemu.qemu_args(self.make.arch.as_ref().unwrap())
     }
 
     pub fn qemu_kernel_args(&self) -> impl Iterator<Item = &str> {
-        self.qemu.kernel_args(&self.make.arch.as_ref().unwrap())
+        self.qemu.kernel_args(self.make.arch.as_ref().unwrap())
     }
 }
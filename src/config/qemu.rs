@@ -1,6 +1,10 @@
-use clap::{value_parser, Arg, ArgAction, ArgMatches, Command, Error, FromArgMatches, ValueHint};
+use clap::builder::PossibleValue;
+use clap::{
+    value_parser, Arg, ArgAction, ArgMatches, Command, Error, FromArgMatches, ValueEnum, ValueHint,
+};
 use serde_derive::Deserialize;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Qemu {
@@ -16,6 +20,13 @@ pub struct Qemu {
     pub mem: String,
     #[serde(default)]
     pub cpus: usize,
+    /// Seconds after which a test run is killed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub boot_mode: BootMode,
+    #[serde(default)]
+    pub network: Network,
 }
 
 impl Qemu {
@@ -50,6 +61,14 @@ impl Qemu {
             .map(String::as_str)
     }
 
+    pub fn firmware(&self, arch: &super::make::Arch) -> Option<&Firmware> {
+        self.arch_config.get(arch).map(|c| &c.firmware)
+    }
+
+    pub fn dtb(&self, arch: &super::make::Arch) -> Option<&Path> {
+        self.arch_config.get(arch).and_then(|c| c.dtb.as_deref())
+    }
+
     pub fn mem(&self) -> &str {
         self.mem.as_str()
     }
@@ -58,6 +77,10 @@ impl Qemu {
         self.cpus
     }
 
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.timeout.map(std::time::Duration::from_secs)
+    }
+
     pub fn augument_args(&self, cmd: Command) -> Command {
         cmd.arg(
             Arg::new("qemu-path")
@@ -95,6 +118,20 @@ impl Qemu {
                 .hide(true)
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("qemu-network")
+                .long("network")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(Network))
+                .help("Give the guest a network, with ssh forwarded from the host"),
+        )
+        .arg(
+            Arg::new("qemu-boot-mode")
+                .long("boot-mode")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(BootMode))
+                .help("Boot the kernel directly or through UEFI firmware"),
+        )
         .group(
             clap::ArgGroup::new("qemu-args")
                 .args(["qemu-path"])
@@ -131,12 +168,15 @@ impl FromArgMatches for Qemu {
     }
 
     fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), Error> {
-        self.path_override = matches.get_one::<String>("qemu-path").map(String::clone);
-        self.mem = matches
-            .get_one::<String>("qemu-mem")
-            .map(String::clone)
-            .unwrap();
-        self.cpus = matches.get_one::<usize>("qemu-cpus").map(|v| *v).unwrap();
+        self.path_override = matches.get_one::<String>("qemu-path").cloned();
+        self.mem = matches.get_one::<String>("qemu-mem").cloned().unwrap();
+        self.cpus = matches.get_one::<usize>("qemu-cpus").copied().unwrap();
+        if let Some(network) = matches.get_one::<Network>("qemu-network") {
+            self.network = *network;
+        }
+        if let Some(mode) = matches.get_one::<BootMode>("qemu-boot-mode") {
+            self.boot_mode = *mode;
+        }
 
         for arg in matches
             .get_many::<String>("qemu-extra-args")
@@ -162,6 +202,71 @@ pub struct QemuConfig {
     pub args: Vec<String>,
     #[serde(default)]
     pub kernel_args: Vec<String>,
+    #[serde(default)]
+    pub firmware: Firmware,
+    /// Device tree to boot with: a `.dtb` built by `make dtbs`, relative to
+    /// `arch/<arch>/boot/dts`, or a `.dts` file on the host.
+    pub dtb: Option<PathBuf>,
+}
+
+/// Firmware images, from `[qemu.<arch>.firmware]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Firmware {
+    /// Passed with `-bios` when booting directly.
+    pub bios: Option<PathBuf>,
+    /// UEFI code flash image, e.g. `OVMF_CODE.fd` or `AAVMF_CODE.fd`.
+    pub code: Option<PathBuf>,
+    /// Template for the UEFI variable store, copied for every boot.
+    pub vars: Option<PathBuf>,
+}
+
+/// How qemu starts the kernel.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootMode {
+    /// Load the kernel with `-kernel`.
+    #[default]
+    Direct,
+    /// Boot UEFI firmware, which starts the kernel's EFI stub from an EFI
+    /// system partition.
+    Uefi,
+}
+
+impl ValueEnum for BootMode {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Direct, Self::Uefi]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Direct => PossibleValue::new("direct"),
+            Self::Uefi => PossibleValue::new("uefi"),
+        })
+    }
+}
+
+/// Network of the guest.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    None,
+    /// A virtio-net NIC on qemu's user mode (SLIRP) network, with a host port
+    /// forwarded to ssh in the guest.
+    User,
+}
+
+impl ValueEnum for Network {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::None, Self::User]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::None => PossibleValue::new("none"),
+            Self::User => PossibleValue::new("user"),
+        })
+    }
 }
 
 fn default_mem() -> String {
@@ -0,0 +1,138 @@
+use crate::config::Config;
+use crate::qmp::Qmp;
+use crate::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// How long qemu gets to create its qmp socket.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A guest kernel panic.
+#[derive(Debug, Clone)]
+pub struct Panic {
+    /// Crash dir with the memory dump, if one could be taken.
+    pub dump: Option<PathBuf>,
+}
+
+/// Collects a memory dump when the guest reports a panic through pvpanic.
+#[derive(Debug, Clone)]
+pub struct CrashWatch {
+    qmp: PathBuf,
+    crash_dir: PathBuf,
+    kernel_bin_dir: PathBuf,
+    /// Whether panics are reported through pvpanic, otherwise a guest reset
+    /// is taken for a panic.
+    pvpanic: bool,
+}
+
+impl CrashWatch {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            qmp: qmp_socket(config),
+            crash_dir: config.make.out_dir().join("crash"),
+            kernel_bin_dir: config.make.kernel_bin_dir(),
+            pvpanic: crate::boot::pvpanic_device(config).is_some(),
+        }
+    }
+
+    /// Watch the VM that is starting in the background, until `exited` is
+    /// set. The thread returns whether the guest panicked.
+    pub fn start(self, exited: Arc<AtomicBool>) -> JoinHandle<Option<Panic>> {
+        std::thread::spawn(move || match self.watch(&exited) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("Failed to watch for guest panics: {e}");
+                None
+            }
+        })
+    }
+
+    fn watch(&self, exited: &AtomicBool) -> Result<Option<Panic>> {
+        let Some(mut qmp) = self.connect(exited) else {
+            debug!("No qmp socket, not watching for panics");
+            return Ok(None);
+        };
+
+        while let Some(event) = qmp.next_event()? {
+            match event.get("event").and_then(Value::as_str) {
+                Some("GUEST_PANICKED") => {
+                    info!("Guest panicked");
+                    let dump = self
+                        .dump(&mut qmp)
+                        .map_err(|e| warn!("Failed to dump guest memory: {e}"))
+                        .ok();
+                    // the guest is paused, nothing more to see
+                    drop(qmp.execute("quit", json!({})));
+                    return Ok(Some(Panic { dump }));
+                }
+                // without a pvpanic device `panic=-1` reboots the guest, which
+                // -no-reboot turns into a shutdown
+                Some("SHUTDOWN")
+                    if !self.pvpanic
+                        && event.pointer("/data/reason").and_then(Value::as_str)
+                            == Some("guest-reset") =>
+                {
+                    info!("Guest reset, assuming a panic");
+                    return Ok(Some(Panic { dump: None }));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn dump(&self, qmp: &mut Qmp) -> Result<PathBuf> {
+        let dir = self.crash_dir.join(crate::timestamp());
+        std::fs::create_dir_all(&dir).context("Failed to create crash dir")?;
+        for file in ["vmlinux", "config"] {
+            let src = self.kernel_bin_dir.join(file);
+            if src.exists() {
+                std::fs::copy(&src, dir.join(file))
+                    .context(format!("Failed to copy {}", src.display()))?;
+            }
+        }
+
+        let vmcore = dir.join("vmcore");
+        eprintln!("Kernel panic, dumping guest memory to {}", vmcore.display());
+        qmp.execute(
+            "dump-guest-memory",
+            json!({
+                "paging": false,
+                "protocol": format!("file:{}", absolute(&vmcore).display()),
+                "format": "elf",
+            }),
+        )?;
+
+        Ok(dir)
+    }
+
+    fn connect(&self, exited: &AtomicBool) -> Option<Qmp> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            match Qmp::connect(&self.qmp) {
+                Ok(qmp) => return Some(qmp),
+                Err(e) if Instant::now() >= deadline || exited.load(Ordering::Relaxed) => {
+                    debug!("{e}");
+                    return None;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+}
+
+pub fn qmp_socket(config: &Config) -> PathBuf {
+    config.make.out_dir().join("vm").join("qmp")
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,217 @@
+use crate::{Context, Error, Result};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// Version of the json deps format understood by this ktest.
+pub const DEPS_VERSION: u32 = 1;
+
+/// Requirements of a test, as reported by `<test> deps`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct TestDeps {
+    pub version: u32,
+    pub arch: Option<String>,
+    pub cpus: Option<usize>,
+    pub mem: Option<String>,
+    pub timeout: Option<u64>,
+    pub kernel_append: Vec<String>,
+    pub kernel_make_append: Vec<String>,
+    pub storage_bus: Option<String>,
+    pub images: Vec<String>,
+    pub scratch_dev_sizes: Vec<String>,
+    pub make_install: Vec<PathBuf>,
+    pub kernel_config_require: Vec<String>,
+    pub qemu_append: Vec<String>,
+}
+
+impl TestDeps {
+    /// Parse the output of `<test> deps json`.
+    ///
+    /// Test libraries predating the json format ignore the extra argument and
+    /// print `key=value` lines instead, which are still accepted.
+    ///
+    /// The json document is printed last, so anything the test prints before
+    /// it is ignored, even if it looks like json.
+    pub fn parse(test: &Path, output: &str) -> Result<Self> {
+        let lines: Vec<&str> = output.lines().collect();
+        match lines.iter().rposition(|l| l.starts_with('{')) {
+            Some(idx) => {
+                for (_, line) in lines.iter().enumerate().filter(|(i, _)| *i != idx) {
+                    warn!(test = %test.display(), line, "Ignoring stray deps output");
+                }
+                Self::parse_json(test, lines[idx])
+            }
+            None => Self::parse_legacy(test, output),
+        }
+    }
+
+    fn parse_json(test: &Path, json: &str) -> Result<Self> {
+        let deps: Self = serde_json::from_str(json)
+            .context(format!("Invalid deps output from {}", test.display()))?;
+
+        if deps.version != DEPS_VERSION {
+            return Err(Error::new(format!(
+                "Unsupported deps format version {} from {}",
+                deps.version,
+                test.display()
+            )));
+        }
+
+        Ok(deps)
+    }
+
+    fn parse_legacy(test: &Path, output: &str) -> Result<Self> {
+        let mut deps = Self {
+            version: 0,
+            ..Default::default()
+        };
+
+        for (nr, line) in output.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = |msg: &str| {
+                Error::new(format!(
+                    "Invalid deps output from {}: line {}: {msg}: `{line}`",
+                    test.display(),
+                    nr + 1
+                ))
+            };
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected key=value"))?;
+
+            debug!(key, value, "Parsing value");
+
+            match key {
+                "ktest_arch" => deps.arch = non_empty(value),
+                "ktest_cpus" => {
+                    deps.cpus = non_empty(value)
+                        .map(|v| v.parse())
+                        .transpose()
+                        .map_err(|_| invalid("invalid number"))?
+                }
+                "ktest_mem" => deps.mem = non_empty(value),
+                "ktest_timeout" => {
+                    deps.timeout = non_empty(value)
+                        .map(|v| v.parse())
+                        .transpose()
+                        .map_err(|_| invalid("invalid number"))?
+                }
+                "ktest_kernel_append" => deps.kernel_append = parse_array(value),
+                "ktest_kernel_make_append" => deps.kernel_make_append = parse_array(value),
+                "ktest_storage_bus" => deps.storage_bus = non_empty(value),
+                "ktest_images" => deps.images = parse_array(value),
+                "ktest_scratch_dev_sizes" => deps.scratch_dev_sizes = parse_array(value),
+                "ktest_make_install" => {
+                    deps.make_install = parse_array(value).into_iter().map(PathBuf::from).collect()
+                }
+                "ktest_kernel_config_require" => deps.kernel_config_require = parse_array(value),
+                "ktest_qemu_append" => deps.qemu_append = parse_array(value),
+                _ => warn!(key, value, "Unknown key"),
+            }
+        }
+
+        Ok(deps)
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Parse a bash array printed as `(a b c)`.
+///
+/// The legacy format does not quote its items, so values containing spaces
+/// cannot be recovered; use the json format for those.
+fn parse_array(value: &str) -> Vec<String> {
+    let value = value.trim();
+    let value = value.strip_prefix('(').unwrap_or(value);
+    let value = value.strip_suffix(')').unwrap_or(value);
+    value.split_whitespace().map(str::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(output: &str) -> Result<TestDeps> {
+        TestDeps::parse(Path::new("test.ktest"), output)
+    }
+
+    #[test]
+    fn json() {
+        let deps = parse(
+            r#"{"version":1,"arch":"x86_64","cpus":4,"mem":null,"timeout":60,"kernel_append":["a b","c"],"make_install":["fs/foo"]}"#,
+        )
+        .unwrap();
+        assert_eq!(deps.version, 1);
+        assert_eq!(deps.arch.as_deref(), Some("x86_64"));
+        assert_eq!(deps.cpus, Some(4));
+        assert_eq!(deps.mem, None);
+        assert_eq!(deps.timeout, Some(60));
+        assert_eq!(deps.kernel_append, ["a b", "c"]);
+        assert_eq!(deps.make_install, [PathBuf::from("fs/foo")]);
+        assert!(deps.images.is_empty());
+    }
+
+    #[test]
+    fn json_uses_last_line() {
+        let deps = parse("{ not json\nsetting up\n{\"version\":1,\"cpus\":2}\n").unwrap();
+        assert_eq!(deps.cpus, Some(2));
+    }
+
+    #[test]
+    fn json_rejects_other_versions() {
+        assert!(parse(r#"{"version":2}"#).is_err());
+        assert!(parse(r#"{"cpus":2}"#).is_err());
+    }
+
+    #[test]
+    fn json_rejects_invalid() {
+        assert!(parse(r#"{"version":1,"cpus":"two"}"#).is_err());
+    }
+
+    #[test]
+    fn legacy() {
+        let deps = parse(
+            "ktest_arch=aarch64\n\
+             ktest_cpus=2\n\
+             ktest_mem=\n\
+             ktest_timeout=30\n\
+             ktest_kernel_append=(foo bar)\n\
+             ktest_images=()\n\
+             ktest_unknown=1\n\
+             \n",
+        )
+        .unwrap();
+        assert_eq!(deps.version, 0);
+        assert_eq!(deps.arch.as_deref(), Some("aarch64"));
+        assert_eq!(deps.cpus, Some(2));
+        assert_eq!(deps.mem, None);
+        assert_eq!(deps.timeout, Some(30));
+        assert_eq!(deps.kernel_append, ["foo", "bar"]);
+        assert!(deps.images.is_empty());
+    }
+
+    #[test]
+    fn legacy_rejects_invalid() {
+        assert!(parse("ktest_cpus=many\n").is_err());
+        assert!(parse("no equals sign\n").is_err());
+    }
+
+    #[test]
+    fn array() {
+        assert_eq!(parse_array("(a  b c)"), ["a", "b", "c"]);
+        assert_eq!(parse_array(" ( ) "), Vec::<String>::new());
+        assert_eq!(parse_array("a b"), ["a", "b"]);
+    }
+}
@@ -0,0 +1,290 @@
+use serde_derive::Serialize;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl core::fmt::Display for Severity {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+            Self::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: u32,
+    pub column: Option<u32>,
+}
+
+impl core::fmt::Display for Location {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)?;
+        if let Some(column) = self.column {
+            write!(f, ":{column}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A GCC or Clang diagnostic.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    #[serde(flatten)]
+    pub location: Location,
+    pub severity: Severity,
+    pub message: String,
+    /// Headers the file was included from, innermost first.
+    pub include_stack: Vec<Location>,
+}
+
+impl core::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.severity, self.message)
+    }
+}
+
+/// Collects diagnostics from compiler output, one line at a time.
+///
+/// File names are reported relative to the kernel source dir when they are
+/// inside of it, independent of the directory the compiler ran in.
+pub struct Parser {
+    kernel_dir: PathBuf,
+    search_dirs: Vec<PathBuf>,
+    include_stack: Vec<Location>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+    /// `search_dirs` are tried in order to resolve relative file names, in
+    /// addition to directories make reports entering.
+    pub fn new(kernel_dir: &Path, search_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            kernel_dir: kernel_dir
+                .canonicalize()
+                .unwrap_or_else(|_| kernel_dir.to_path_buf()),
+            search_dirs,
+            include_stack: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) {
+        if let Some(dir) = entering_directory(line) {
+            self.search_dirs.insert(0, PathBuf::from(dir));
+            return;
+        }
+
+        let included = line
+            .strip_prefix("In file included from ")
+            .or_else(|| line.trim_start().strip_prefix("from "));
+        if let Some(loc) = included {
+            if line.starts_with("In file") {
+                self.include_stack.clear();
+            }
+            let loc = loc.trim_end_matches([',', ':']);
+            if let Some(loc) = self.parse_location(loc) {
+                self.include_stack.push(loc);
+            }
+            return;
+        }
+
+        let Some((loc, severity, message)) = split_diagnostic(line) else {
+            return;
+        };
+        let Some(location) = self.parse_location(loc) else {
+            return;
+        };
+
+        self.diagnostics.push(Diagnostic {
+            location,
+            severity,
+            message: message.to_string(),
+            include_stack: if severity == Severity::Note {
+                Vec::new()
+            } else {
+                std::mem::take(&mut self.include_stack)
+            },
+        });
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == severity)
+            .count()
+    }
+
+    fn parse_location(&self, loc: &str) -> Option<Location> {
+        let mut parts = loc.rsplitn(3, ':');
+        let last = parts.next()?.parse::<u32>().ok()?;
+        let middle = parts.next()?;
+
+        let (file, line, column) = match middle.parse::<u32>() {
+            Ok(line) => (parts.next()?, line, Some(last)),
+            Err(_) => {
+                // no column, the rest belongs to the file name
+                let file = loc.rsplit_once(':')?.0;
+                (file, last, None)
+            }
+        };
+        if file.is_empty() || file.contains(' ') {
+            return None;
+        }
+
+        Some(Location {
+            file: self.relative_path(Path::new(file)),
+            line,
+            column,
+        })
+    }
+
+    fn relative_path(&self, file: &Path) -> PathBuf {
+        let path = if file.is_absolute() {
+            file.to_path_buf()
+        } else {
+            self.search_dirs
+                .iter()
+                .chain(std::iter::once(&self.kernel_dir))
+                .map(|dir| dir.join(file))
+                .find(|p| p.exists())
+                .unwrap_or_else(|| file.to_path_buf())
+        };
+        let path = path.canonicalize().unwrap_or_else(|_| normalize(&path));
+
+        path.strip_prefix(&self.kernel_dir)
+            .map(Path::to_path_buf)
+            .unwrap_or(path)
+    }
+}
+
+fn entering_directory(line: &str) -> Option<&str> {
+    let (_, dir) = line.split_once(": Entering directory ")?;
+    Some(dir.trim_matches(|c| c == '\'' || c == '`'))
+}
+
+fn split_diagnostic(line: &str) -> Option<(&str, Severity, &str)> {
+    for (marker, severity) in [
+        (": fatal error: ", Severity::Error),
+        (": error: ", Severity::Error),
+        (": warning: ", Severity::Warning),
+        (": note: ", Severity::Note),
+    ] {
+        if let Some((loc, message)) = line.split_once(marker) {
+            return Some((loc, severity, message));
+        }
+    }
+    None
+}
+
+/// Remove `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if ret.file_name().is_some() => {
+                ret.pop();
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser() -> Parser {
+        Parser::new(Path::new("/nonexistent/linux"), Vec::new())
+    }
+
+    #[test]
+    fn diagnostic() {
+        let mut p = parser();
+        p.parse_line("/nonexistent/linux/kernel/fork.c:12:5: error: expected ';' before '}' token");
+        p.parse_line("fs/../mm/slab.c:7: warning: unused variable 'x' [-Wunused-variable]");
+        p.parse_line("  CC      kernel/fork.o");
+        p.parse_line("make[2]: *** [scripts/Makefile.build:243: kernel/fork.o] Error 1");
+
+        assert_eq!(p.diagnostics.len(), 2);
+        assert_eq!(
+            p.diagnostics[0].to_string(),
+            "kernel/fork.c:12:5: error: expected ';' before '}' token"
+        );
+        assert_eq!(
+            p.diagnostics[1].location,
+            Location {
+                file: PathBuf::from("mm/slab.c"),
+                line: 7,
+                column: None,
+            }
+        );
+        assert_eq!(p.diagnostics[1].severity, Severity::Warning);
+        assert_eq!(p.count(Severity::Error), 1);
+        assert_eq!(p.count(Severity::Warning), 1);
+    }
+
+    #[test]
+    fn fatal_error() {
+        let mut p = parser();
+        p.parse_line("init/main.c:1:10: fatal error: foo.h: No such file or directory");
+        assert_eq!(p.diagnostics[0].severity, Severity::Error);
+        assert_eq!(p.diagnostics[0].message, "foo.h: No such file or directory");
+    }
+
+    #[test]
+    fn include_stack() {
+        let mut p = parser();
+        p.parse_line("In file included from include/linux/a.h:3,");
+        p.parse_line("                 from kernel/fork.c:10:");
+        p.parse_line("include/linux/b.h:5:1: warning: no newline at end of file");
+        p.parse_line("include/linux/b.h:4:1: note: previous definition is here");
+        p.parse_line("kernel/exit.c:2:1: warning: unused function");
+
+        let [warning, note, other] = &p.diagnostics[..] else {
+            panic!("expected 3 diagnostics: {:?}", p.diagnostics);
+        };
+        let stack = warning
+            .include_stack
+            .iter()
+            .map(Location::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(stack, ["include/linux/a.h:3", "kernel/fork.c:10"]);
+        assert!(note.include_stack.is_empty());
+        assert!(other.include_stack.is_empty());
+    }
+
+    #[test]
+    fn not_a_diagnostic() {
+        let mut p = parser();
+        p.parse_line("ld: warning: creating DT_TEXTREL in a PIE");
+        p.parse_line("some text: error: with spaces:1: in it");
+        assert!(p.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn entering() {
+        assert_eq!(
+            entering_directory("make[1]: Entering directory '/src/linux/build'"),
+            Some("/src/linux/build")
+        );
+        assert_eq!(entering_directory("make[1]: Leaving directory '/x'"), None);
+    }
+
+    #[test]
+    fn normalize_path() {
+        assert_eq!(normalize(Path::new("./a/b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalize(Path::new("../a")), PathBuf::from("../a"));
+    }
+}
@@ -7,6 +7,7 @@ pub enum ErrorKind {
     Io(std::io::Error),
     Errno(nix::errno::Errno),
     Utf8(std::str::Utf8Error),
+    Json(serde_json::Error),
 
     Clap(clap::Error),
 }
@@ -54,6 +55,7 @@ impl core::fmt::Display for Error {
             ErrorKind::Io(err) => err.fmt(f),
             ErrorKind::Errno(err) => err.fmt(f),
             ErrorKind::Utf8(err) => err.fmt(f),
+            ErrorKind::Json(err) => err.fmt(f),
 
             ErrorKind::Clap(err) => err.fmt(f),
         }
@@ -66,6 +68,7 @@ impl std::error::Error for Error {
             ErrorKind::Io(err) => err,
             ErrorKind::Errno(err) => err,
             ErrorKind::Utf8(err) => err,
+            ErrorKind::Json(err) => err,
 
             _ => return None,
         })
@@ -75,7 +78,7 @@ impl std::error::Error for Error {
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
         trace!("creating error from std::io::Error: {:?}", value);
-        let exit_code = value.raw_os_error().map(i32::from);
+        let exit_code = value.raw_os_error();
         let kind = ErrorKind::Io(value);
 
         Self {
@@ -88,7 +91,7 @@ impl From<std::io::Error> for Error {
 
 impl From<nix::errno::Errno> for Error {
     fn from(errno: nix::errno::Errno) -> Self {
-        let exit_code = (errno as i32).try_into().ok();
+        let exit_code = Some(errno as i32);
         let kind = ErrorKind::Errno(errno);
 
         Self {
@@ -109,6 +112,16 @@ impl From<core::str::Utf8Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self {
+            exit_code: None,
+            context: String::new(),
+            kind: ErrorKind::Json(value),
+        }
+    }
+}
+
 impl From<clap::Error> for Error {
     fn from(err: clap::Error) -> Self {
         let kind = ErrorKind::Clap(err);
@@ -123,7 +136,7 @@ impl From<clap::Error> for Error {
 
 impl From<std::num::ParseIntError> for Error {
     fn from(value: ParseIntError) -> Self {
-        todo!()
+        Self::new(value.to_string())
     }
 }
 
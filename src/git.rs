@@ -0,0 +1,27 @@
+use crate::{Context, Error, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// A `git` command running in `dir`.
+pub fn git(dir: impl AsRef<Path>) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(dir);
+    cmd
+}
+
+/// Run git in `dir` and return its stdout, failing if git does.
+pub fn output(dir: impl AsRef<Path>, args: &[&str]) -> Result<String> {
+    let out = git(dir)
+        .args(args)
+        .output()
+        .context("Failed to execute git")?;
+    if !out.status.success() {
+        return Err(Error::new(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))
+        .set_exit_code(out.status.code()));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
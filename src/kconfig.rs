@@ -1,6 +1,8 @@
-use crate::config::Config;
+use crate::config::{Config, KconfigCheck};
 use crate::make::MakeCmd;
-use crate::{Error, Result};
+use crate::{Context, Error, Result};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tracing::*;
@@ -14,23 +16,57 @@ where
     let mut config_file = config.make.make_build_dir();
     config_file.push(".config");
 
+    let base = &config.make.base_config;
+    let base_file = config.make.make_build_dir().join(".config.ktest-base");
+    if config_file.exists() {
+        // configs from before the base was recorded were all allnoconfig
+        let old_base = std::fs::read_to_string(&base_file)
+            .unwrap_or_else(|_| BaseConfig::AllNoConfig.marker());
+        if old_base.trim() != base.marker() {
+            info!(
+                "Existing config was built from `{}`, regenerating from `{base}`",
+                old_base.trim()
+            );
+            std::fs::remove_file(&config_file).context("Failed to remove old config")?;
+        }
+    }
+
     if !config_file.exists() {
-        debug!("Running allnoconfig");
-        MakeCmd::new(config, Some("allnoconfig"), args)?.run()?;
+        match base {
+            BaseConfig::File(path) => {
+                debug!("Copying base config from {}", path.display());
+                std::fs::create_dir_all(config.make.make_build_dir())?;
+                std::fs::copy(path, &config_file)
+                    .context(format!("Failed to copy base config {}", path.display()))?;
+            }
+            base => {
+                let target = base.make_target().expect("only files have no target");
+                debug!("Running {target}");
+                MakeCmd::new(config, Some(target), args)?.run_logged(config)?;
+            }
+        }
 
-        debug!("Clear full config");
-        let status = Command::new("sed")
-            .arg("-i")
-            .arg("-e")
-            .arg("s/\\(CONFIG_.*\\)=.*/# \\1 is not set/")
-            .arg(config_file.as_os_str())
-            .status()?;
-        trace!("sed status: {}", status);
+        if *base == BaseConfig::AllNoConfig {
+            debug!("Clear full config");
+            let status = Command::new("sed")
+                .arg("-i")
+                .arg("-e")
+                .arg("s/\\(CONFIG_.*\\)=.*/# \\1 is not set/")
+                .arg(config_file.as_os_str())
+                .status()?;
+            trace!("sed status: {}", status);
+        }
 
-        // TODO: replace all with n
+        std::fs::write(&base_file, base.marker()).context("Failed to record base config")?;
     }
 
-    for (key, val) in &config.make.kconfig {
+    let required = config.make.required_kconfig();
+    for (key, val) in &config.make.optional_kconfig {
+        if !required.contains_key(key) {
+            set_config(config, &config_file, key, val)?;
+        }
+    }
+    for (key, val) in &required {
         set_config(config, &config_file, key, val)?;
     }
 
@@ -38,69 +74,558 @@ where
 }
 
 #[instrument(level = "debug", skip(config))]
-pub fn set_config(config: &Config, file: &Path, key: &str, value: &str) -> Result {
+pub fn set_config(config: &Config, file: &Path, key: &str, value: &KconfigValue) -> Result {
     let mut ktool = PathBuf::from(&config.make.kernel_dir);
     ktool.push("scripts");
     ktool.push("config");
 
+    let mut cmd = Command::new(ktool);
+    cmd.arg("--file").arg(file);
+    match value {
+        KconfigValue::Yes => cmd.arg("--enable").arg(key),
+        KconfigValue::Module => cmd.arg("--module").arg(key),
+        KconfigValue::No => cmd.arg("--disable").arg(key),
+        KconfigValue::Int(_) | KconfigValue::Hex(_) => {
+            cmd.arg("--set-val").arg(key).arg(value.to_string())
+        }
+        KconfigValue::String(s) => cmd.arg("--set-str").arg(key).arg(s),
+    };
+
     debug!("Setting config option");
-    let status = Command::new(ktool)
-        .arg("--file")
-        .arg(file)
-        .arg("--set-val")
-        .arg(key)
-        .arg(value)
-        .status()?;
-    // TODO: propagate error?
+    let status = cmd.status()?;
     trace!("config status status: {}", status);
 
+    if !status.success() {
+        info!("Failed to run config tool: {}", status);
+        return Err(
+            Error::new(format!("Failed to set config option `{key}`")).set_exit_code(status.code())
+        );
+    }
+
     Ok(())
 }
 
+/// Check every requested symbol against the generated config.
+///
+/// All mismatches are reported in a single table. Depending on
+/// `make.kconfig_check` they either fail the build or are only warned about.
 pub fn check_configs(config: &Config, file: &Path) -> Result {
-    for (key, val) in &config.make.kconfig {
-        check_config(config, file, key, val)?;
+    let current = read_config(file)?;
+
+    let required = config.make.required_kconfig();
+    let mismatches = mismatches(&current, &required);
+
+    if mismatches.is_empty() {
+        info!("validated config");
+        return Ok(());
     }
-    info!("validated config");
+
+    let rows = mismatches
+        .iter()
+        .map(|(key, requested, actual)| {
+            [
+                key.to_string(),
+                requested.to_string(),
+                actual.to_string(),
+                config.make.kconfig_source(key),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print!(
+        "{}",
+        table(["SYMBOL", "REQUESTED", "ACTUAL", "SOURCE"], &rows)
+    );
+
+    let msg = format!("{} config option(s) not set as requested", mismatches.len());
+    match config.make.kconfig_check {
+        KconfigCheck::Warn => {
+            warn!("{msg}");
+            Ok(())
+        }
+        KconfigCheck::Error => Err(Error::new(msg).set_exit_code(Some(1))),
+    }
+}
+
+/// All symbols of `required` not set as requested in `current`, with their
+/// actual value, sorted by name.
+fn mismatches<'a>(
+    current: &HashMap<String, KconfigValue>,
+    required: &'a HashMap<String, KconfigValue>,
+) -> Vec<(&'a str, &'a KconfigValue, KconfigValue)> {
+    let mut mismatches = required
+        .iter()
+        .filter_map(|(key, val)| {
+            check_config(current, key, val)
+                .err()
+                .map(|actual| (key.as_str(), val, actual))
+        })
+        .collect::<Vec<_>>();
+    mismatches.sort_by_key(|(key, _, _)| *key);
+    mismatches
+}
+
+/// Check a single symbol, returning the actual value on mismatch.
+#[instrument(level = "debug", skip(current))]
+pub fn check_config(
+    current: &HashMap<String, KconfigValue>,
+    key: &str,
+    value: &KconfigValue,
+) -> Result<(), KconfigValue> {
+    let c = current.get(key).unwrap_or(&KconfigValue::No);
+
+    if !value.matches(c) {
+        debug!("Config mismatch: `{key}`: `{c}` != `{value}`");
+        return Err(c.clone());
+    }
+
     Ok(())
 }
 
-#[instrument(level = "debug", skip(config))]
-pub fn check_config(config: &Config, file: &Path, key: &str, value: &str) -> Result {
-    let mut ktool = PathBuf::from(&config.make.kernel_dir);
-    ktool.push("scripts");
-    ktool.push("config");
+fn table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
 
-    let out = Command::new(ktool)
-        .arg("--file")
-        .arg(file)
-        .arg("-s")
-        .arg(key)
-        .output()?;
+    let format_row = |row: &[&str]| {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
 
-    if !out.status.success() {
-        info!("Failed to run config tool: {}", out.status);
-        return Err(Error::new("Failed to run config tool").set_exit_code(out.status.code()));
+    let mut out = format_row(&header);
+    for row in rows {
+        out += &format_row(&row.each_ref().map(String::as_str));
     }
+    out
+}
 
-    let c = core::str::from_utf8(&out.stdout)?;
-    let c = if c == "undef" { "n" } else { c }.trim();
+/// Read all symbols set in a `.config` file.
+///
+/// Symbols that are explicitly not set are returned as [`KconfigValue::No`],
+/// symbols missing from the file are not returned at all.
+pub fn read_config(file: &Path) -> Result<HashMap<String, KconfigValue>> {
+    Ok(read_fragment(file)?.into_iter().collect())
+}
 
-    if c != value {
-        return Err(
-            Error::new(format!("Config mismatch: `{key}`: `{c}` != `{value}`"))
-                .set_exit_code(Some(1)),
-        );
+/// Read a `.config` style file, keeping the order of the symbols.
+pub fn read_fragment(file: &Path) -> Result<Vec<(String, KconfigValue)>> {
+    let content = std::fs::read_to_string(file)
+        .context(format!("Failed to read config file {}", file.display()))?;
+    parse_fragment(&file.display().to_string(), &content)
+}
+
+/// Parse the contents of a `.config` style file, `name` is only used for
+/// errors.
+fn parse_fragment(name: &str, content: &str) -> Result<Vec<(String, KconfigValue)>> {
+    let mut ret = Vec::new();
+    for (nr, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if let Some(key) = line
+            .strip_prefix("# CONFIG_")
+            .and_then(|l| l.strip_suffix(" is not set"))
+        {
+            ret.push((key.to_string(), KconfigValue::No));
+        } else if let Some(line) = line.strip_prefix("CONFIG_") {
+            let (key, value) = line
+                .split_once('=')
+                .context(format!("{name}:{}: Invalid config line: `{line}`", nr + 1))?;
+            let value = value
+                .parse()
+                .map_err(|e: Error| Error::new(format!("{name}:{}: {e}", nr + 1)))?;
+            ret.push((key.to_string(), value));
+        }
     }
 
-    Ok(())
+    Ok(ret)
+}
+
+/// Config a new `.config` is generated from, before applying `make.kconfig`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BaseConfig {
+    /// `allnoconfig` with every remaining option turned off.
+    #[default]
+    AllNoConfig,
+    TinyConfig,
+    DefConfig,
+    /// A saved `.config`, for example from a distribution kernel.
+    File(PathBuf),
+}
+
+impl BaseConfig {
+    fn make_target(&self) -> Option<&'static str> {
+        match self {
+            Self::AllNoConfig => Some("allnoconfig"),
+            Self::TinyConfig => Some("tinyconfig"),
+            Self::DefConfig => Some("defconfig"),
+            Self::File(_) => None,
+        }
+    }
+
+    /// Identifies the base in the marker stored next to the generated config.
+    fn marker(&self) -> String {
+        match self {
+            Self::File(path) => format!(
+                "file:{}",
+                path.canonicalize().as_deref().unwrap_or(path).display()
+            ),
+            base => base.to_string(),
+        }
+    }
+}
+
+impl core::fmt::Display for BaseConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::File(path) => path.display().fmt(f),
+            base => base
+                .make_target()
+                .expect("only files have no target")
+                .fmt(f),
+        }
+    }
+}
+
+impl std::str::FromStr for BaseConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "allnoconfig" => Self::AllNoConfig,
+            "tinyconfig" => Self::TinyConfig,
+            "defconfig" => Self::DefConfig,
+            "" => return Err(Error::new("Empty base config")),
+            path => Self::File(PathBuf::from(path)),
+        })
+    }
+}
+
+impl TryFrom<String> for BaseConfig {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
 }
 
-pub fn parse(str: &str) -> Result<(String, String)> {
-    let parts = str.split("=").collect::<Vec<_>>();
-    match parts.len() {
-        1 => Ok((parts[0].to_string(), "y".to_string())),
-        2 => Ok((parts[0].to_string(), parts[1].to_string())),
-        _ => Err(Error::new(format!("Invalid config option: {}", str))),
+/// Value of a single kconfig symbol.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum KconfigValue {
+    Yes,
+    Module,
+    No,
+    Int(i64),
+    Hex(u64),
+    String(String),
+}
+
+impl KconfigValue {
+    /// Compare a requested value with the value found in a `.config`.
+    ///
+    /// Numbers compare equal independent of their base, and strings compare by
+    /// content independent of whether they were quoted.
+    pub fn matches(&self, actual: &Self) -> bool {
+        match (self.as_number(), actual.as_number()) {
+            (Some(a), Some(b)) => return a == b,
+            (Some(_), None) | (None, Some(_)) => {}
+            (None, None) => {
+                if self == actual {
+                    return true;
+                }
+            }
+        }
+
+        match (self, actual) {
+            (Self::String(a), b) | (b, Self::String(a)) => *a == b.unquoted(),
+            _ => false,
+        }
+    }
+
+    fn as_number(&self) -> Option<i128> {
+        match self {
+            Self::Int(v) => Some(i128::from(*v)),
+            Self::Hex(v) => Some(i128::from(*v)),
+            _ => None,
+        }
+    }
+
+    fn unquoted(&self) -> String {
+        match self {
+            Self::String(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+impl core::fmt::Display for KconfigValue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Yes => write!(f, "y"),
+            Self::Module => write!(f, "m"),
+            Self::No => write!(f, "n"),
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Hex(v) => write!(f, "{v:#x}"),
+            Self::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    if c == '"' || c == '\\' {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{c}")?;
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for KconfigValue {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "y" => Self::Yes,
+            "m" => Self::Module,
+            "n" => Self::No,
+            s if s.starts_with('"') => {
+                let inner = s
+                    .strip_prefix('"')
+                    .and_then(|s| s.strip_suffix('"'))
+                    .ok_or_else(|| Error::new(format!("Unterminated string value: {s}")))?;
+                let mut out = String::with_capacity(inner.len());
+                let mut chars = inner.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => out.extend(chars.next()),
+                        '"' => return Err(Error::new(format!("Unescaped quote in value: {s}"))),
+                        c => out.push(c),
+                    }
+                }
+                Self::String(out)
+            }
+            s if s.starts_with("0x") || s.starts_with("0X") => u64::from_str_radix(&s[2..], 16)
+                .map(Self::Hex)
+                .map_err(|e| Error::new(format!("Invalid hex value `{s}`: {e}")))?,
+            s => s
+                .parse()
+                .map(Self::Int)
+                .unwrap_or_else(|_| Self::String(s.to_string())),
+        })
+    }
+}
+
+impl TryFrom<String> for KconfigValue {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Parse a `SYMBOL[=VALUE]` kconfig option, defaulting to `y`.
+///
+/// Only the first `=` separates the symbol from its value, so values may
+/// themselves contain `=`. A leading `CONFIG_` is stripped from the symbol.
+pub fn parse(str: &str) -> Result<(String, KconfigValue)> {
+    let (key, value) = match str.split_once('=') {
+        Some((key, value)) => (key, value.parse()?),
+        None => (str, KconfigValue::Yes),
+    };
+    let key = key.trim();
+    let key = key.strip_prefix("CONFIG_").unwrap_or(key);
+
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::new(format!("Invalid config option: {}", str)));
+    }
+
+    Ok((key.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_parse() {
+        assert_eq!("y".parse::<KconfigValue>().unwrap(), KconfigValue::Yes);
+        assert_eq!("m".parse::<KconfigValue>().unwrap(), KconfigValue::Module);
+        assert_eq!("n".parse::<KconfigValue>().unwrap(), KconfigValue::No);
+        assert_eq!(
+            "-12".parse::<KconfigValue>().unwrap(),
+            KconfigValue::Int(-12)
+        );
+        assert_eq!(
+            "0x1F".parse::<KconfigValue>().unwrap(),
+            KconfigValue::Hex(0x1f)
+        );
+        assert_eq!(
+            r#""a \"b\" \\c""#.parse::<KconfigValue>().unwrap(),
+            KconfigValue::String(r#"a "b" \c"#.to_string())
+        );
+        assert_eq!(
+            "bare".parse::<KconfigValue>().unwrap(),
+            KconfigValue::String("bare".to_string())
+        );
+    }
+
+    #[test]
+    fn value_parse_invalid() {
+        assert!(r#""unterminated"#.parse::<KconfigValue>().is_err());
+        assert!(r#""a"b""#.parse::<KconfigValue>().is_err());
+        assert!("0xzz".parse::<KconfigValue>().is_err());
+    }
+
+    #[test]
+    fn value_display_roundtrip() {
+        for s in ["y", "m", "n", "42", "0x10", r#""a \"quoted\" \\ value""#] {
+            let value: KconfigValue = s.parse().unwrap();
+            assert_eq!(value.to_string(), s);
+            assert_eq!(value.to_string().parse::<KconfigValue>().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn value_matches() {
+        assert!(KconfigValue::Int(16).matches(&KconfigValue::Hex(0x10)));
+        assert!(!KconfigValue::Int(16).matches(&KconfigValue::Int(17)));
+        assert!(KconfigValue::String("42".to_string()).matches(&KconfigValue::Int(42)));
+        assert!(KconfigValue::String("y".to_string()).matches(&KconfigValue::Yes));
+        assert!(!KconfigValue::Yes.matches(&KconfigValue::Module));
+    }
+
+    #[test]
+    fn option_parse() {
+        assert_eq!(
+            parse("KASAN").unwrap(),
+            ("KASAN".to_string(), KconfigValue::Yes)
+        );
+        assert_eq!(
+            parse("CONFIG_LOG_BUF_SHIFT=17").unwrap(),
+            ("LOG_BUF_SHIFT".to_string(), KconfigValue::Int(17))
+        );
+        assert_eq!(
+            parse(r#"CMDLINE="a=b c""#).unwrap(),
+            (
+                "CMDLINE".to_string(),
+                KconfigValue::String("a=b c".to_string())
+            )
+        );
+        assert!(parse("").is_err());
+        assert!(parse("BAD-NAME=y").is_err());
+        assert!(parse("X=\"open").is_err());
+    }
+
+    #[test]
+    fn config_mismatches() {
+        let current = HashMap::from([
+            ("A".to_string(), KconfigValue::Yes),
+            ("B".to_string(), KconfigValue::Module),
+            ("C".to_string(), KconfigValue::Hex(0x10)),
+            ("D".to_string(), KconfigValue::No),
+        ]);
+        let required = HashMap::from([
+            ("A".to_string(), KconfigValue::Yes),
+            ("B".to_string(), KconfigValue::Yes),
+            ("C".to_string(), KconfigValue::Int(16)),
+            ("D".to_string(), KconfigValue::No),
+            ("E".to_string(), KconfigValue::No),
+            ("F".to_string(), KconfigValue::Module),
+        ]);
+
+        assert_eq!(
+            mismatches(&current, &required),
+            [
+                ("B", &KconfigValue::Yes, KconfigValue::Module),
+                ("F", &KconfigValue::Module, KconfigValue::No),
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatch_table() {
+        let rows = [
+            ["LONG_SYMBOL", "y", "n", ""].map(String::from),
+            ["B", "0x10", "m", "frag.config"].map(String::from),
+        ];
+        assert_eq!(
+            table(["SYMBOL", "REQUESTED", "ACTUAL", "SOURCE"], &rows),
+            "SYMBOL       REQUESTED  ACTUAL  SOURCE\n\
+             LONG_SYMBOL  y          n\n\
+             B            0x10       m       frag.config\n"
+        );
+    }
+
+    #[test]
+    fn base_config() {
+        assert_eq!(
+            "allnoconfig".parse::<BaseConfig>().unwrap(),
+            BaseConfig::AllNoConfig
+        );
+        assert_eq!(
+            "tinyconfig".parse::<BaseConfig>().unwrap(),
+            BaseConfig::TinyConfig
+        );
+        assert_eq!(
+            "defconfig".parse::<BaseConfig>().unwrap(),
+            BaseConfig::DefConfig
+        );
+        assert_eq!(
+            "/boot/config-6.1".parse::<BaseConfig>().unwrap(),
+            BaseConfig::File(PathBuf::from("/boot/config-6.1"))
+        );
+        assert!("".parse::<BaseConfig>().is_err());
+        assert_eq!(BaseConfig::default(), BaseConfig::AllNoConfig);
+    }
+
+    #[test]
+    fn base_config_marker() {
+        for base in ["allnoconfig", "tinyconfig", "defconfig"] {
+            let config: BaseConfig = base.parse().unwrap();
+            assert_eq!(config.to_string(), base);
+            assert_eq!(config.marker(), base);
+            assert_eq!(config.make_target(), Some(base));
+        }
+
+        let file = BaseConfig::File(PathBuf::from("/nonexistent/config"));
+        assert_eq!(file.marker(), "file:/nonexistent/config");
+        assert_eq!(file.make_target(), None);
+    }
+
+    #[test]
+    fn fragment() {
+        let fragment = parse_fragment(
+            "frag",
+            "# comment\n\
+             CONFIG_A=y\n\
+             \x20 # CONFIG_B is not set\n\
+             CONFIG_C=\"x\"\n\
+             CONFIG_A=m\n",
+        )
+        .unwrap();
+        assert_eq!(
+            fragment,
+            [
+                ("A".to_string(), KconfigValue::Yes),
+                ("B".to_string(), KconfigValue::No),
+                ("C".to_string(), KconfigValue::String("x".to_string())),
+                ("A".to_string(), KconfigValue::Module),
+            ]
+        );
+    }
+
+    #[test]
+    fn fragment_errors_have_line_numbers() {
+        let err = parse_fragment("frag", "CONFIG_A=y\nCONFIG_B\n").unwrap_err();
+        assert!(err.to_string().starts_with("frag:2:"), "{err}");
+        let err = parse_fragment("frag", "\nCONFIG_A=\"open\n").unwrap_err();
+        assert!(err.to_string().starts_with("frag:2:"), "{err}");
     }
 }
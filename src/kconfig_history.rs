@@ -0,0 +1,216 @@
+use crate::config::Config;
+use crate::kconfig::KconfigValue;
+use crate::{Context, Error, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// A `.config` saved after a build, named `<timestamp>-<release>.config`.
+///
+/// The timestamp has microseconds, so builds finishing within the same second
+/// don't overwrite each other. Snapshots from before that are still read.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub timestamp: String,
+    pub release: String,
+}
+
+impl Snapshot {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".config")?;
+        // the timestamp has a fixed length, the release may contain dashes
+        let (timestamp, release) = name.split_at_checked(crate::TIMESTAMP_LEN)?;
+        let (timestamp, release) = match release.strip_prefix('.') {
+            Some(rest) => {
+                let (micros, release) = rest.split_at_checked(6)?;
+                (format!("{timestamp}.{micros}"), release)
+            }
+            None => (timestamp.to_string(), release),
+        };
+        let release = release.strip_prefix('-')?.to_string();
+
+        Some(Self {
+            path,
+            timestamp,
+            release,
+        })
+    }
+}
+
+pub fn history_dir(config: &Config) -> PathBuf {
+    config.make.out_dir().join("config-history")
+}
+
+/// Save the current `.config` of the build dir to the config history.
+#[instrument(level = "debug", skip(config))]
+pub fn snapshot(config: &Config, release: &str) -> Result<PathBuf> {
+    let dir = history_dir(config);
+    std::fs::create_dir_all(&dir).context("Failed to create config history dir")?;
+
+    let path = dir.join(format!("{}-{release}.config", timestamp()));
+    std::fs::copy(config.make.make_build_dir().join(".config"), &path)
+        .context("Failed to save config snapshot")?;
+    debug!("Saved config snapshot {}", path.display());
+
+    Ok(path)
+}
+
+/// [`crate::timestamp`] with microseconds.
+fn timestamp() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(time::macros::format_description!(
+            "[year][month][day]-[hour][minute][second].[subsecond digits:6]"
+        ))
+        .expect("timestamp format is valid")
+}
+
+/// All snapshots, oldest first.
+pub fn snapshots(config: &Config) -> Result<Vec<Snapshot>> {
+    let dir = history_dir(config);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ret = std::fs::read_dir(&dir)
+        .context("Failed to read config history")?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| Snapshot::from_path(e.path()))
+        .collect::<Vec<_>>();
+    ret.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ret)
+}
+
+/// Find a snapshot by its index in [`snapshots`], its file name or a path.
+///
+/// Negative indices count from the newest snapshot, `-1` being the latest.
+pub fn resolve(snapshots: &[Snapshot], spec: &str) -> Result<PathBuf> {
+    if let Ok(idx) = spec.parse::<isize>() {
+        let idx = if idx < 0 {
+            snapshots.len().checked_sub(idx.unsigned_abs())
+        } else {
+            Some(idx as usize)
+        };
+        return idx
+            .and_then(|i| snapshots.get(i))
+            .map(|s| s.path.clone())
+            .context(format!("No config snapshot `{spec}`"));
+    }
+
+    if let Some(snapshot) = snapshots
+        .iter()
+        .find(|s| s.path.file_name().and_then(|n| n.to_str()) == Some(spec))
+    {
+        return Ok(snapshot.path.clone());
+    }
+
+    let path = PathBuf::from(spec);
+    if path.exists() {
+        Ok(path)
+    } else {
+        Err(Error::new(format!("No config snapshot `{spec}`")))
+    }
+}
+
+/// A symbol that differs between two configs.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<KconfigValue>,
+    pub new: Option<KconfigValue>,
+}
+
+impl core::fmt::Display for Change {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, " {} {old} -> {new}", self.key),
+            (Some(old), None) => write!(f, "-{} {old}", self.key),
+            (None, Some(new)) => write!(f, "+{} {new}", self.key),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Symbol level differences between two `.config` files, sorted by symbol.
+///
+/// Comments and ordering are ignored, only the values of symbols count. A
+/// symbol that is not set and a missing symbol are both `n` and not reported.
+pub fn diff(a: &Path, b: &Path) -> Result<Vec<Change>> {
+    let old = crate::kconfig::read_config(a)?;
+    let new = crate::kconfig::read_config(b)?;
+
+    Ok(diff_values(&old, &new))
+}
+
+fn diff_values(
+    old: &HashMap<String, KconfigValue>,
+    new: &HashMap<String, KconfigValue>,
+) -> Vec<Change> {
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|key| {
+            let (o, n) = (old.get(key), new.get(key));
+            let no = &KconfigValue::No;
+            if o.unwrap_or(no).matches(n.unwrap_or(no)) {
+                return None;
+            }
+            Some(Change {
+                key: key.clone(),
+                old: o.cloned(),
+                new: n.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(values: &[(&str, KconfigValue)]) -> HashMap<String, KconfigValue> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_names() {
+        let s =
+            Snapshot::from_path(PathBuf::from("20261019-081500.123456-6.1.0-rc1.config")).unwrap();
+        assert_eq!(s.timestamp, "20261019-081500.123456");
+        assert_eq!(s.release, "6.1.0-rc1");
+
+        let s = Snapshot::from_path(PathBuf::from("20261019-081500-6.1.0-rc1.config")).unwrap();
+        assert_eq!(s.timestamp, "20261019-081500");
+        assert_eq!(s.release, "6.1.0-rc1");
+
+        assert!(Snapshot::from_path(PathBuf::from("20261019-081500-6.1.0")).is_none());
+        assert!(Snapshot::from_path(PathBuf::from("short.config")).is_none());
+    }
+
+    #[test]
+    fn unset_and_missing_are_equal() {
+        let old = config(&[("A", KconfigValue::No), ("B", KconfigValue::Yes)]);
+        let new = config(&[("B", KconfigValue::Yes), ("C", KconfigValue::No)]);
+        assert!(diff_values(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn changes() {
+        let old = config(&[("A", KconfigValue::Yes), ("B", KconfigValue::Int(1))]);
+        let new = config(&[
+            ("B", KconfigValue::Hex(2)),
+            ("C", KconfigValue::Module),
+            ("D", KconfigValue::No),
+        ]);
+        let changes = diff_values(&old, &new)
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(changes, ["-A y", " B 1 -> 0x2", "+C m"]);
+    }
+}
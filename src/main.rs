@@ -1,17 +1,46 @@
 use clap::{Arg, FromArgMatches};
 use tracing::trace;
 
+mod archive;
+mod bisect;
 mod boot;
 mod build;
 mod commands;
 mod config;
+mod crash;
+mod deps;
+mod diagnostics;
 mod err;
+mod git;
 mod kconfig;
+mod kconfig_history;
 mod make;
+mod manifest;
+mod monitor;
+mod qmp;
+mod snapshot;
+mod source;
+mod ssh;
+mod warnings;
+mod worktree;
 
 pub use err::{Context, Error, Result};
 
+/// Length of the strings returned by [`timestamp`].
+pub const TIMESTAMP_LEN: usize = 15;
+
+/// Current UTC time, formatted to sort correctly in file names.
+pub fn timestamp() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(time::macros::format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))
+        .expect("timestamp format is valid")
+}
+
 pub fn main() {
+    make::inherit_jobserver();
+
     let config = config::Config::new().expect("Failed to read config");
     config.init().expect("Failed to initialize async runtime");
 
@@ -33,9 +62,13 @@ fn run_main(mut config: config::Config) -> Result {
         .subcommand(commands::make::command(&config))
         .subcommand(commands::config::command(&config))
         .subcommand(commands::oldconfig::command(&config))
+        .subcommand(commands::kconfig::command(&config))
         .subcommand(commands::build::command(&config))
         .subcommand(commands::boot::command(&config))
-        .subcommand(commands::run::command(&config));
+        .subcommand(commands::run::command(&config))
+        .subcommand(commands::export::command(&config))
+        .subcommand(commands::ssh::command(&config))
+        .subcommand(commands::bisect::command(&config));
     let app = config.make.augument_args(app);
 
     let matches = app.get_matches();
@@ -44,15 +77,20 @@ fn run_main(mut config: config::Config) -> Result {
         .make
         .update_from_arg_matches(&matches)
         .context("Failed to parse matches")?;
+    config.verbose = matches.get_count("verbose");
     trace!("Loaded config: {config:?}");
 
     match matches.subcommand().context("No subcomand provided")? {
-        ("make", matches) => commands::make::run(&mut config, &matches)?,
-        ("config", matches) => commands::config::run(&mut config, &matches)?,
-        ("oldconfig", matches) => commands::oldconfig::run(&mut config, &matches)?,
-        ("build", matches) => commands::build::run(&mut config, &matches)?,
-        ("boot", matches) => commands::boot::run(&mut config, &matches)?,
-        ("run", matches) => commands::run::run(&mut config, &matches)?,
+        ("make", matches) => commands::make::run(&config, matches)?,
+        ("config", matches) => commands::config::run(&config, matches)?,
+        ("oldconfig", matches) => commands::oldconfig::run(&config, matches)?,
+        ("kconfig", matches) => commands::kconfig::run(&config, matches)?,
+        ("build", matches) => commands::build::run(&mut config, matches)?,
+        ("boot", matches) => commands::boot::run(&mut config, matches)?,
+        ("run", matches) => commands::run::run(&mut config, matches)?,
+        ("export", matches) => commands::export::run(&config, matches)?,
+        ("ssh", matches) => commands::ssh::run(&config, matches)?,
+        ("bisect", matches) => commands::bisect::run(&mut config, matches)?,
 
         _ => return Err(Error::new("Unknown subcommand")),
     };
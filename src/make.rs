@@ -1,19 +1,48 @@
 use std::ffi::OsStr;
-use std::process::Command;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::time::Instant;
 
-use crate::config::Config;
+use crate::config::{Config, DiagnosticsFormat};
+use crate::diagnostics::{self, Diagnostic, Severity};
 use crate::{Context, Error, Result};
 use tracing::*;
 
-pub fn create_jobserver(config: &Config) -> Result<jobserver::Client> {
-    let jobs = config.make.jobs.unwrap();
+static JOBSERVER: OnceLock<jobserver::Client> = OnceLock::new();
+
+/// Take over the jobserver of a parent make, if ktest runs under one.
+///
+/// Has to run at startup, before the file descriptors named in `MAKEFLAGS`
+/// could be closed or reused.
+pub fn inherit_jobserver() {
+    // SAFETY: called first thing in main, nothing has touched the inherited fds yet
+    if let Some(client) = unsafe { jobserver::Client::from_env() } {
+        drop(JOBSERVER.set(client));
+    }
+}
+
+/// The jobserver shared by all make invocations of this ktest run.
+///
+/// Uses the inherited jobserver if there is one, otherwise one is created with
+/// `make.jobs` tokens on first use.
+pub fn jobserver(config: &Config) -> Result<jobserver::Client> {
+    if let Some(client) = JOBSERVER.get() {
+        trace!("Reusing jobserver");
+        return Ok(client.clone());
+    }
 
+    let jobs = config.make.jobs.unwrap();
     debug!("Creating jobserver with {jobs} jobs");
-    jobserver::Client::new(jobs).context("Failed to create jobserver")
+    let client = jobserver::Client::new(jobs).context("Failed to create jobserver")?;
+    Ok(JOBSERVER.get_or_init(|| client).clone())
 }
 
 pub struct MakeCmd {
     pub cmd: Command,
+    /// Keeps the jobserver pipes open until make has finished.
+    #[allow(dead_code)]
     pub jobserver: jobserver::Client,
 }
 
@@ -23,22 +52,21 @@ impl MakeCmd {
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        let jobserver = create_jobserver(config)?;
+        let jobserver = jobserver(config)?;
 
         let mut cmd = Command::new(&config.make.path);
         cmd.current_dir(
-            std::path::Path::new(&config.make.kernel_dir)
+            Path::new(&config.make.kernel_dir)
                 .canonicalize()
                 .context("Failed to resolve kernel source directory")?,
         );
         jobserver.configure_make(&mut cmd);
         cmd.arg(config.make.make_arch_arg());
-        cmd.arg(config.make.make_build_dir_arg());
+        cmd.arg(config.make.make_build_dir_arg()?);
         cmd.arg(format!(
             "INSTALL_MOD_PATH={}",
-            config
-                .make
-                .kernel_bin_dir()
+            std::path::absolute(config.make.kernel_bin_dir())
+                .context("Failed to resolve kernel bin dir")?
                 .to_str()
                 .context("Invalid kernel bin dir")?
         ));
@@ -51,9 +79,137 @@ impl MakeCmd {
         Ok(Self { cmd, jobserver })
     }
 
+    /// Create a plain make invocation in `dir`, sharing ktest's jobserver
+    /// settings but none of the kernel specific arguments.
+    pub fn new_in<I, S>(config: &Config, dir: &Path, command: Option<&str>, args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let jobserver = jobserver(config)?;
+
+        let mut cmd = Command::new(&config.make.path);
+        cmd.current_dir(dir);
+        jobserver.configure_make(&mut cmd);
+        if let Some(command) = command {
+            cmd.arg(command);
+        }
+        cmd.args(args);
+
+        Ok(Self { cmd, jobserver })
+    }
+
     pub fn run(&mut self) -> Result {
-        debug!(
-            "Running {} {}",
+        self.log_cmd();
+        let status = self.cmd.status().context("Error executing make")?;
+
+        if !status.success() {
+            info!("Failed to run make: {}", status);
+            Err(Error::new("Failed to run make").set_exit_code(status.code()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run make with its output going to the build log instead of the terminal.
+    ///
+    /// Only a progress line is shown while make runs, and a summary of the
+    /// compiler errors if it fails. With `-v` the output is passed through as
+    /// well. Compiler diagnostics are collected in both cases.
+    pub fn run_logged(&mut self, config: &Config) -> Result {
+        let verbose = config.verbose > 0;
+        self.log_cmd();
+
+        let log_path = build_log(config)?;
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .context("Failed to open build log")?;
+        writeln!(log, "$ {}", self.cmdline())?;
+
+        let mut search_dirs = vec![config.make.make_build_dir()];
+        search_dirs.extend(self.cmd.get_current_dir().map(Path::to_path_buf));
+        let mut diagnostics =
+            diagnostics::Parser::new(Path::new(&config.make.kernel_dir), search_dirs);
+
+        let mut child = self
+            .cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Error executing make")?;
+
+        let (tx, rx) = mpsc::channel();
+        let readers = [
+            forward_lines(
+                child.stdout.take().expect("stdout is piped"),
+                false,
+                tx.clone(),
+            ),
+            forward_lines(child.stderr.take().expect("stderr is piped"), true, tx),
+        ];
+
+        let mut progress = Progress::new(!verbose);
+        let mut errors = Vec::new();
+        for (is_stderr, line) in rx {
+            writeln!(log, "{line}")?;
+            diagnostics.parse_line(&line);
+            if verbose {
+                if is_stderr {
+                    eprintln!("{line}");
+                } else {
+                    println!("{line}");
+                }
+            }
+            progress.update(&line);
+            if is_error_line(&line) {
+                errors.push(line);
+            }
+        }
+        progress.finish();
+
+        for reader in readers {
+            drop(reader.join());
+        }
+        let status = child.wait().context("Error executing make")?;
+
+        if config.make.diagnostics == DiagnosticsFormat::Json {
+            for diagnostic in &diagnostics.diagnostics {
+                println!("{}", serde_json::to_string(diagnostic)?);
+            }
+        }
+
+        let failed = !status.success();
+        if failed {
+            info!("Failed to run make: {}", status);
+            match config.make.diagnostics {
+                DiagnosticsFormat::Summary => print_summary(&diagnostics, &errors, verbose),
+                // stdout only has the json, keep e.g. linker errors visible
+                DiagnosticsFormat::Json if !verbose => {
+                    for line in &errors {
+                        eprintln!("{line}");
+                    }
+                }
+                DiagnosticsFormat::Json => {}
+            }
+            eprintln!("Full build log: {}", log_path.display());
+        }
+        DIAGNOSTICS
+            .lock()
+            .expect("diagnostics lock poisoned")
+            .append(&mut diagnostics.diagnostics);
+
+        if failed {
+            Err(Error::new("Failed to run make").set_exit_code(status.code()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn cmdline(&self) -> String {
+        format!(
+            "{} {}",
             self.cmd
                 .get_program()
                 .to_str()
@@ -63,14 +219,137 @@ impl MakeCmd {
                 .filter_map(|a| a.to_str())
                 .collect::<Vec<_>>()
                 .join(" ")
+        )
+    }
+
+    fn log_cmd(&self) {
+        debug!("Running {}", self.cmdline());
+    }
+}
+
+static BUILD_LOG: OnceLock<PathBuf> = OnceLock::new();
+static DIAGNOSTICS: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+
+/// Take the compiler diagnostics collected by [`MakeCmd::run_logged`] so far.
+pub fn take_diagnostics() -> Vec<Diagnostic> {
+    std::mem::take(&mut DIAGNOSTICS.lock().expect("diagnostics lock poisoned"))
+}
+
+/// The log all make output of this ktest run goes to in quiet mode.
+pub fn build_log(config: &Config) -> Result<PathBuf> {
+    if let Some(path) = BUILD_LOG.get() {
+        return Ok(path.clone());
+    }
+
+    let dir = config.make.out_dir().join("logs");
+    std::fs::create_dir_all(&dir).context("Failed to create log dir")?;
+    let path = dir.join(format!("build-{}.log", crate::timestamp()));
+    Ok(BUILD_LOG.get_or_init(|| path).clone())
+}
+
+fn forward_lines(
+    pipe: impl Read + Send + 'static,
+    is_stderr: bool,
+    tx: mpsc::Sender<(bool, String)>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut pipe = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        while let Ok(n) = pipe.read_until(b'\n', &mut buf) {
+            if n == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buf);
+            if tx.send((is_stderr, line.trim_end().to_string())).is_err() {
+                break;
+            }
+            buf.clear();
+        }
+    })
+}
+
+/// Summarize a failed build, preferring compiler diagnostics over raw lines.
+fn print_summary(diagnostics: &diagnostics::Parser, errors: &[String], verbose: bool) {
+    let errs = diagnostics.count(Severity::Error);
+    let warnings = diagnostics.count(Severity::Warning);
+
+    if errs == 0 {
+        // e.g. linker errors, which are not compiler diagnostics
+        if !verbose {
+            for line in errors {
+                eprintln!("{line}");
+            }
+        }
+        return;
+    }
+
+    eprintln!("Build failed with {errs} error(s) and {warnings} warning(s):");
+    for diagnostic in diagnostics
+        .diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+    {
+        eprintln!("  {diagnostic}");
+        for loc in &diagnostic.include_stack {
+            eprintln!("      included from {loc}");
+        }
+    }
+}
+
+fn is_error_line(line: &str) -> bool {
+    line.contains("error:")
+        || line.contains("Error:")
+        || line.contains("undefined reference")
+        || (line.starts_with("make") && line.contains("***"))
+}
+
+/// One line showing the last kbuild step, e.g. `CC kernel/fork.o`.
+struct Progress {
+    start: Instant,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            enabled: enabled && std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, line: &str) {
+        if !self.enabled {
+            return;
+        }
+        // kbuild prints steps as `  CC      kernel/fork.o`
+        let Some(step) = line.strip_prefix("  ") else {
+            return;
+        };
+        let mut parts = step.split_whitespace();
+        let (Some(tag), Some(target)) = (parts.next(), parts.next()) else {
+            return;
+        };
+        if !tag
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            return;
+        }
+
+        let elapsed = self.start.elapsed().as_secs();
+        let target = target
+            .get(target.len().saturating_sub(60)..)
+            .unwrap_or(target);
+        eprint!(
+            "\r\x1b[K[{:02}:{:02}] {tag:<8}{target}",
+            elapsed / 60,
+            elapsed % 60
         );
-        let status = self.cmd.status().context("Error executing make")?;
+    }
 
-        if !status.success() {
-            info!("Failed to run make: {}", status);
-            Err(Error::new("Failed to run make").set_exit_code(status.code()))
-        } else {
-            Ok(())
+    fn finish(&mut self) {
+        if self.enabled {
+            eprint!("\r\x1b[K");
         }
     }
 }
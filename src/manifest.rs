@@ -0,0 +1,289 @@
+use crate::config::Config;
+use crate::kconfig::KconfigValue;
+use crate::{Context, Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// A file installed into the kernel bin dir by a build.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Artifact {
+    pub role: String,
+    /// Relative to the kernel bin dir.
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Everything a build installed, written last so an interrupted install is
+/// detectable.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub arch: String,
+    pub release: String,
+    /// `CONFIG_CC_VERSION_TEXT` of the build.
+    pub toolchain: Option<String>,
+    pub make_args: Vec<String>,
+    pub artifacts: Vec<Artifact>,
+}
+
+pub fn path(config: &Config) -> PathBuf {
+    config.make.kernel_bin_dir().join("manifest.json")
+}
+
+impl Manifest {
+    /// Checksum everything currently installed in the kernel bin dir.
+    ///
+    /// Only modules of `release` are recorded, trees of older builds left in
+    /// `lib/modules` are not part of this kernel.
+    #[instrument(level = "debug", skip(config))]
+    pub fn collect(config: &Config, release: &str, make_args: Vec<String>) -> Result<Self> {
+        let base = config.make.kernel_bin_dir();
+        let mut files = Vec::new();
+        walk(&base, &mut files).context("Failed to list installed files")?;
+        files.sort();
+
+        let mut artifacts = Vec::new();
+        for file in files {
+            let rel = file.strip_prefix(&base).unwrap_or(&file).to_path_buf();
+            if rel == Path::new("manifest.json") || !current_release(&rel, release) {
+                continue;
+            }
+            let (size, sha256) = checksum(&file)?;
+            artifacts.push(Artifact {
+                role: role(&rel).to_string(),
+                path: rel,
+                size,
+                sha256,
+            });
+        }
+
+        let toolchain = crate::kconfig::read_config(&base.join("config"))
+            .ok()
+            .and_then(|mut c| c.remove("CC_VERSION_TEXT"))
+            .map(|v| match v {
+                KconfigValue::String(s) => s,
+                v => v.to_string(),
+            });
+
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            arch: config.make.arch.map(|a| a.to_string()).unwrap_or_default(),
+            release: release.to_string(),
+            toolchain,
+            make_args,
+            artifacts,
+        })
+    }
+
+    pub fn write(&self, config: &Config) -> Result {
+        std::fs::write(path(config), serde_json::to_vec_pretty(self)?)
+            .context("Failed to write build manifest")
+    }
+
+    pub fn read(config: &Config) -> Result<Self> {
+        let path = path(config);
+        let data = std::fs::read(&path).context(format!(
+            "No build manifest at {}, the kernel was not (completely) installed",
+            path.display()
+        ))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Check that every artifact is installed unmodified and that there is a
+    /// kernel image to boot.
+    #[instrument(level = "debug", skip(self, config))]
+    pub fn verify(&self, config: &Config) -> Result {
+        let base = config.make.kernel_bin_dir();
+        let mut problems = Vec::new();
+
+        if !self.artifacts.iter().any(|a| a.role == "image") {
+            problems.push("no kernel image recorded".to_string());
+        }
+
+        for artifact in &self.artifacts {
+            let file = base.join(&artifact.path);
+            match checksum(&file) {
+                Err(_) => problems.push(format!("{} is missing", artifact.path.display())),
+                Ok((size, _)) if size != artifact.size => problems.push(format!(
+                    "{} has size {size}, expected {}",
+                    artifact.path.display(),
+                    artifact.size
+                )),
+                Ok((_, sha256)) if sha256 != artifact.sha256 => problems.push(format!(
+                    "{} has a different checksum",
+                    artifact.path.display()
+                )),
+                Ok(_) => {}
+            }
+        }
+
+        if problems.is_empty() {
+            debug!("Verified {} artifacts", self.artifacts.len());
+            return Ok(());
+        }
+
+        for problem in &problems {
+            eprintln!("{}: {problem}", base.display());
+        }
+        Err(Error::new(format!(
+            "Installed kernel {} does not match its build manifest, rebuild it",
+            self.release
+        )))
+    }
+}
+
+/// Read and verify the manifest of the installed kernel.
+pub fn verify(config: &Config) -> Result<Manifest> {
+    let manifest = Manifest::read(config)?;
+    manifest.verify(config)?;
+    Ok(manifest)
+}
+
+fn role(path: &Path) -> &'static str {
+    if path.starts_with("lib/modules") {
+        return match path.extension().and_then(|e| e.to_str()) {
+            Some("ko") => "module",
+            _ => "module-metadata",
+        };
+    }
+
+    match path.to_str() {
+        Some("vmlinuz") => "image",
+        Some("vmlinux") => "vmlinux",
+        Some("config") => "config",
+        Some("dtb") => "dtb",
+        Some("source.json") => "source",
+        _ => "other",
+    }
+}
+
+/// Whether `path` is not a module file, or one of `release`.
+fn current_release(path: &Path, release: &str) -> bool {
+    match path.strip_prefix("lib/modules") {
+        Ok(rest) => rest.starts_with(release),
+        Err(_) => true,
+    }
+}
+
+/// Regular files below `dir`. Symlinks, like the `build` link modules_install
+/// creates back into the build tree, are not followed.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if ty.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+pub fn checksum(file: &Path) -> Result<(u64, String)> {
+    let mut f = std::fs::File::open(file).context(format!("Failed to open {}", file.display()))?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut f, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Arch;
+
+    fn config(name: &str) -> Config {
+        let mut config = Config::embedded();
+        config.make.out_dir = std::env::temp_dir()
+            .join(format!("ktest-{name}-{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        config.make.arch = Some(Arch::X86_64);
+        config
+    }
+
+    fn install(config: &Config, files: &[(&str, &str)]) {
+        let base = config.make.kernel_bin_dir();
+        for (path, content) in files {
+            let path = base.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn roles() {
+        assert_eq!(role(Path::new("vmlinuz")), "image");
+        assert_eq!(role(Path::new("vmlinux")), "vmlinux");
+        assert_eq!(role(Path::new("config")), "config");
+        assert_eq!(role(Path::new("lib/modules/6.8/kernel/a.ko")), "module");
+        assert_eq!(
+            role(Path::new("lib/modules/6.8/modules.dep")),
+            "module-metadata"
+        );
+        assert_eq!(role(Path::new("usr/bin/tool")), "other");
+    }
+
+    #[test]
+    fn releases() {
+        assert!(current_release(Path::new("vmlinuz"), "6.8"));
+        assert!(current_release(Path::new("lib/modules/6.8/a.ko"), "6.8"));
+        assert!(!current_release(Path::new("lib/modules/6.7/a.ko"), "6.8"));
+        assert!(!current_release(
+            Path::new("lib/modules/6.8-rc1/a.ko"),
+            "6.8"
+        ));
+    }
+
+    #[test]
+    fn collect_and_verify() {
+        let config = config("manifest");
+        install(
+            &config,
+            &[
+                ("vmlinuz", "image"),
+                ("config", "CONFIG_CC_VERSION_TEXT=\"gcc 13\"\n"),
+                ("lib/modules/6.8/kernel/a.ko", "a"),
+                ("lib/modules/6.7/kernel/old.ko", "old"),
+            ],
+        );
+
+        let manifest = Manifest::collect(&config, "6.8", vec!["W=1".to_string()]).unwrap();
+        let paths = manifest
+            .artifacts
+            .iter()
+            .map(|a| a.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["config", "lib/modules/6.8/kernel/a.ko", "vmlinuz"]);
+        assert_eq!(manifest.arch, "x86_64");
+        assert_eq!(manifest.toolchain.as_deref(), Some("gcc 13"));
+        manifest.verify(&config).unwrap();
+
+        install(&config, &[("vmlinuz", "imagf")]);
+        assert!(manifest.verify(&config).is_err());
+        install(&config, &[("vmlinuz", "image")]);
+        manifest.verify(&config).unwrap();
+
+        std::fs::remove_file(
+            config
+                .make
+                .kernel_bin_dir()
+                .join("lib/modules/6.8/kernel/a.ko"),
+        )
+        .unwrap();
+        assert!(manifest.verify(&config).is_err());
+
+        let mut no_image = manifest.clone();
+        no_image.artifacts.retain(|a| a.role != "image");
+        no_image.artifacts.retain(|a| a.role != "module");
+        assert!(no_image.verify(&config).is_err());
+
+        std::fs::remove_dir_all(config.make.out_dir()).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::{Context, Error, Result};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+const PROMPT: &str = "(qemu) ";
+
+/// The human monitor socket of the VM, see `QemuCmd::new`.
+pub fn socket(config: &Config) -> PathBuf {
+    config.make.out_dir().join("vm").join("mon")
+}
+
+/// Run a human monitor command in the running VM and return its output.
+pub fn hmp(config: &Config, command: &str) -> Result<String> {
+    hmp_at(&socket(config), command)
+}
+
+/// [`hmp`] on the monitor listening on `socket`.
+#[instrument(level = "debug")]
+pub fn hmp_at(socket: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(socket).context("Failed to connect to qemu monitor")?;
+
+    // banner
+    read_to_prompt(&mut stream)?;
+
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    let out = read_to_prompt(&mut stream)?;
+    // the monitor echoes the command line
+    let out = out
+        .split_once('\n')
+        .map(|(_, rest)| rest)
+        .unwrap_or_default()
+        .replace('\r', "");
+    trace!("{command}: {out}");
+
+    if out.contains("Error") {
+        return Err(Error::new(format!(
+            "qemu monitor: {command}: {}",
+            out.trim()
+        )));
+    }
+    Ok(out)
+}
+
+fn read_to_prompt(stream: &mut UnixStream) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    while !buf.ends_with(PROMPT.as_bytes()) {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let out = String::from_utf8_lossy(&buf);
+    Ok(out.strip_suffix(PROMPT).unwrap_or(&out).to_string())
+}
@@ -0,0 +1,71 @@
+use crate::{Context, Error, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use tracing::*;
+
+/// A QMP connection, in command mode.
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Qmp {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let writer = UnixStream::connect(socket).context("Failed to connect to qmp")?;
+        let mut ret = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+
+        // greeting
+        ret.read()?.context("qmp closed before the greeting")?;
+        ret.execute("qmp_capabilities", json!({}))?;
+        Ok(ret)
+    }
+
+    /// Run a command, returning its result. Events received in between are
+    /// dropped.
+    pub fn execute(&mut self, command: &str, arguments: Value) -> Result<Value> {
+        debug!("qmp: {command} {arguments}");
+        let msg = json!({ "execute": command, "arguments": arguments });
+        writeln!(self.writer, "{msg}")?;
+
+        loop {
+            let msg = self
+                .read()?
+                .context(format!("qmp closed while running {command}"))?;
+            if let Some(ret) = msg.get("return") {
+                return Ok(ret.clone());
+            }
+            if let Some(err) = msg.get("error") {
+                return Err(Error::new(format!(
+                    "qmp {command}: {}",
+                    err.get("desc")
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown error")
+                )));
+            }
+        }
+    }
+
+    /// Wait for the next event, `None` if qemu exits first.
+    pub fn next_event(&mut self) -> Result<Option<Value>> {
+        while let Some(msg) = self.read()? {
+            if msg.get("event").is_some() {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(&mut self) -> Result<Option<Value>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        trace!("qmp: {}", line.trim());
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
@@ -0,0 +1,289 @@
+use crate::boot::{Outcome, QemuCmd};
+use crate::config::{BootMode, Config};
+use crate::{Context, Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Printed on the console by the guest once it is booted and waiting for a
+/// test, see `wait_for_test` in `lib/prelude.sh`.
+pub const READY_MARKER: &str = "ktest: ready";
+
+/// Kernel argument telling the guest to wait for a test instead of running
+/// the one it was started with.
+const WAIT_FOR_TEST_ARG: &str = "ktest.wait_for_test";
+
+/// How long the guest gets to boot, independent of the test timeout.
+const READY_TIMEOUT: Duration = Duration::from_secs(300);
+
+const NAME: &str = "ktest";
+
+/// What a snapshot was saved from. It is only restored when both match.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct SnapshotInfo {
+    image_sha256: String,
+    qemu_args: Vec<String>,
+}
+
+fn dir(config: &Config) -> PathBuf {
+    config.make.out_dir().join("snapshot")
+}
+
+fn image(config: &Config) -> PathBuf {
+    dir(config).join("vmstate.qcow2")
+}
+
+fn info_file(config: &Config) -> PathBuf {
+    dir(config).join("snapshot.json")
+}
+
+/// Boot, wait for the guest to be ready and save a snapshot of the VM.
+#[instrument(level = "debug", skip(config))]
+pub fn snapshot_after_boot(config: &Config) -> Result {
+    let config = &snapshot_config(config);
+    let mut qemu = QemuCmd::new(config)?;
+    attach(config, &mut qemu)?;
+    discard(config)?;
+
+    let (mut child, _stdin, ready) = spawn(&mut qemu, false)?;
+    wait_ready(&mut child, &ready)?;
+    save(config, &qemu)?;
+
+    // the monitor connection just closes on quit
+    drop(crate::monitor::hmp(config, "quit"));
+    drop(child.wait());
+
+    Ok(())
+}
+
+/// Run `test` in a VM restored from the snapshot, taking and saving a fresh
+/// one first if there is no valid snapshot.
+#[instrument(level = "debug", skip(config))]
+pub fn run_from_snapshot(config: &Config, test: &Path) -> Result<Outcome> {
+    let test = guest_path(test)?;
+
+    let config = &snapshot_config(config);
+    let mut qemu = QemuCmd::new(config)?;
+    attach(config, &mut qemu)?;
+    let restore = is_valid(config, &qemu)?;
+    if restore {
+        println!("Restoring VM snapshot");
+    } else {
+        discard(config)?;
+    }
+
+    let (mut child, mut stdin, ready) = spawn(&mut qemu, restore)?;
+    if !restore {
+        wait_ready(&mut child, &ready)?;
+        save(config, &qemu)?;
+    }
+
+    writeln!(stdin, "{}", test.display()).context("Failed to start test in the guest")?;
+    qemu.wait(&mut child, config.qemu.timeout())
+}
+
+/// `config` with the guest told to wait for a test once booted.
+fn snapshot_config(config: &Config) -> Config {
+    let mut config = config.clone();
+    config
+        .qemu
+        .extra_kernel_args
+        .push(WAIT_FOR_TEST_ARG.to_string());
+    config
+}
+
+/// `test` as the guest finds it: relative to the directory ktest runs in,
+/// which is where the guest runs its tests from.
+fn guest_path(test: &Path) -> Result<PathBuf> {
+    if !test.exists() {
+        return Err(Error::new(format!(
+            "Test {} does not exist",
+            test.display()
+        )));
+    }
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let rel = if test.is_absolute() {
+        test.strip_prefix(&cwd).map_err(|_| {
+            Error::new(format!(
+                "Test {} is outside of {}, the guest can't run it",
+                test.display(),
+                cwd.display()
+            ))
+        })?
+    } else {
+        test
+    };
+    // a bare name would be looked up in $PATH
+    if rel.parent() == Some(Path::new("")) {
+        return Ok(Path::new(".").join(rel));
+    }
+    Ok(rel.to_path_buf())
+}
+
+/// Attach the qcow2 image snapshots are saved to, creating it if needed.
+fn attach(config: &Config, qemu: &mut QemuCmd) -> Result {
+    if config.qemu.boot_mode == BootMode::Uefi {
+        // the raw firmware flash can't be snapshotted
+        return Err(Error::new("VM snapshots are not supported with UEFI boots"));
+    }
+
+    std::fs::create_dir_all(dir(config)).context("Failed to create snapshot dir")?;
+    if !image(config).exists() {
+        create_image(config)?;
+    }
+
+    qemu.cmd.arg("-drive").arg(format!(
+        "if=none,id=ktest-snapshot,format=qcow2,file={}",
+        image(config).display()
+    ));
+    Ok(())
+}
+
+fn create_image(config: &Config) -> Result {
+    debug!("Running qemu-img create {}", image(config).display());
+    let out = Command::new("qemu-img")
+        .args(["create", "-q", "-f", "qcow2"])
+        .arg(image(config))
+        .arg("1M")
+        .output()
+        .context("Failed to execute qemu-img")?;
+    if !out.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&out.stderr));
+        return Err(Error::new("Failed to create snapshot image").set_exit_code(out.status.code()));
+    }
+    Ok(())
+}
+
+fn info(config: &Config, qemu: &QemuCmd) -> Result<SnapshotInfo> {
+    let (_, image_sha256) =
+        crate::manifest::checksum(&config.make.kernel_bin_dir().join("vmlinuz"))?;
+
+    // the kernel command line doesn't matter once booted
+    let mut qemu_args = Vec::new();
+    let mut args = qemu.args().into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-append" {
+            args.next();
+        } else {
+            qemu_args.push(arg);
+        }
+    }
+
+    Ok(SnapshotInfo {
+        image_sha256,
+        qemu_args,
+    })
+}
+
+/// Whether the saved snapshot was taken of the current kernel and VM.
+fn is_valid(config: &Config, qemu: &QemuCmd) -> Result<bool> {
+    let Some(saved) = std::fs::read(info_file(config))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<SnapshotInfo>(&data).ok())
+    else {
+        return Ok(false);
+    };
+
+    if saved == info(config, qemu)? {
+        return Ok(true);
+    }
+    println!("Kernel or VM changed since the snapshot was taken, discarding it");
+    Ok(false)
+}
+
+/// Forget the saved snapshot, keeping an empty image in its place.
+fn discard(config: &Config) -> Result {
+    if !info_file(config).exists() {
+        return Ok(());
+    }
+    std::fs::remove_file(info_file(config)).context("Failed to remove snapshot info")?;
+    std::fs::remove_file(image(config)).context("Failed to remove snapshot image")?;
+    create_image(config)
+}
+
+fn save(config: &Config, qemu: &QemuCmd) -> Result {
+    crate::monitor::hmp(config, &format!("savevm {NAME}"))?;
+    std::fs::write(
+        info_file(config),
+        serde_json::to_vec_pretty(&info(config, qemu)?)?,
+    )
+    .context("Failed to write snapshot info")?;
+
+    println!("Saved VM snapshot to {}", image(config).display());
+    Ok(())
+}
+
+/// Start qemu with the console on pipes. Console output is passed through,
+/// the returned channel fires when the guest prints [`READY_MARKER`].
+fn spawn(qemu: &mut QemuCmd, restore: bool) -> Result<(Child, ChildStdin, mpsc::Receiver<()>)> {
+    if restore {
+        qemu.cmd.arg("-loadvm").arg(NAME);
+    }
+    qemu.cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+
+    let mut child = qemu.spawn()?;
+    let stdin = child.stdin.take().context("Missing qemu stdin")?;
+    let mut stdout = child.stdout.take().context("Missing qemu stdout")?;
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut out = std::io::stdout();
+        let mut chunk = [0u8; 4096];
+        // enough of the output to find a marker split across reads
+        let mut tail = String::new();
+        let mut ready = false;
+
+        while let Ok(n) = stdout.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            drop(out.write_all(&chunk[..n]));
+            drop(out.flush());
+
+            if !ready {
+                tail.push_str(&String::from_utf8_lossy(&chunk[..n]));
+                if tail.contains(READY_MARKER) {
+                    ready = true;
+                    let _ = tx.send(());
+                }
+                let keep = tail.len().saturating_sub(READY_MARKER.len());
+                let keep = (keep..tail.len())
+                    .find(|i| tail.is_char_boundary(*i))
+                    .unwrap_or(0);
+                tail.drain(..keep);
+            }
+        }
+    });
+
+    Ok((child, stdin, rx))
+}
+
+fn wait_ready(child: &mut Child, ready: &mpsc::Receiver<()>) -> Result {
+    let deadline = Instant::now() + READY_TIMEOUT;
+
+    loop {
+        match ready.recv_timeout(Duration::from_millis(100)) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                drop(child.wait());
+                return Err(Error::new("qemu exited before the guest was ready"));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+
+        if child.try_wait()?.is_some() {
+            return Err(Error::new("qemu exited before the guest was ready"));
+        }
+        if Instant::now() >= deadline {
+            drop(child.kill());
+            drop(child.wait());
+            return Err(
+                Error::new("Timed out waiting for the guest to be ready").set_exit_code(124)
+            );
+        }
+    }
+}
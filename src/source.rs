@@ -0,0 +1,123 @@
+use crate::config::Config;
+use crate::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use tracing::*;
+
+/// Revision and state of the kernel tree a build was made from.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SourceInfo {
+    pub release: String,
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+    pub dirty: bool,
+    /// sha256 of `git diff HEAD`, only set for dirty trees.
+    pub diff_hash: Option<String>,
+}
+
+impl SourceInfo {
+    /// Inspect the kernel tree. Trees not managed by git only get a release.
+    #[instrument(level = "debug", skip(config))]
+    pub fn collect(config: &Config, release: &str) -> Self {
+        let dir = &config.make.kernel_dir;
+        let git = |args: &[&str]| {
+            crate::git::output(dir, args)
+                .map_err(|e| debug!("{e}"))
+                .ok()
+        };
+
+        let mut info = Self {
+            release: release.to_string(),
+            ..Default::default()
+        };
+
+        info.commit = git(&["rev-parse", "HEAD"]).map(|s| s.trim().to_string());
+        if info.commit.is_none() {
+            return info;
+        }
+        info.branch = git(&["symbolic-ref", "--short", "-q", "HEAD"])
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        info.dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+            .is_some_and(|s| !s.trim().is_empty());
+        if info.dirty {
+            info.diff_hash = git(&["diff", "HEAD", "--binary"])
+                .map(|diff| format!("{:x}", Sha256::digest(diff.as_bytes())));
+        }
+
+        info
+    }
+
+    fn path(config: &Config) -> PathBuf {
+        config.make.kernel_bin_dir().join("source.json")
+    }
+
+    pub fn write(&self, config: &Config) -> Result {
+        std::fs::write(Self::path(config), serde_json::to_vec_pretty(self)?)
+            .context("Failed to write kernel source info")
+    }
+
+    /// Read the info recorded for the installed kernel, if there is any.
+    pub fn read(config: &Config) -> Result<Option<Self>> {
+        let path = Self::path(config);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = std::fs::read(&path).context("Failed to read kernel source info")?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+}
+
+impl core::fmt::Display for SourceInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.release)?;
+        if let Some(commit) = &self.commit {
+            write!(f, " from {commit}")?;
+        }
+        if let Some(branch) = &self.branch {
+            write!(f, " ({branch})")?;
+        }
+        if self.dirty {
+            write!(f, " with uncommitted changes")?;
+            if let Some(hash) = &self.diff_hash {
+                write!(f, " {}", &hash[..12.min(hash.len())])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let mut info = SourceInfo {
+            release: "6.8.0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(info.to_string(), "6.8.0");
+
+        info.commit = Some("0123456789abcdef".to_string());
+        assert_eq!(info.to_string(), "6.8.0 from 0123456789abcdef");
+
+        info.branch = Some("master".to_string());
+        info.dirty = true;
+        assert_eq!(
+            info.to_string(),
+            "6.8.0 from 0123456789abcdef (master) with uncommitted changes"
+        );
+
+        info.diff_hash = Some("a".repeat(64));
+        assert_eq!(
+            info.to_string(),
+            "6.8.0 from 0123456789abcdef (master) with uncommitted changes aaaaaaaaaaaa"
+        );
+
+        info.diff_hash = Some("abc".to_string());
+        assert!(info.to_string().ends_with(" abc"));
+    }
+}
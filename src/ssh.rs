@@ -0,0 +1,156 @@
+use crate::config::Config;
+use crate::{Context, Error, Result};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// fw_cfg file the guest reads its authorized ssh keys from.
+pub const FW_CFG_KEY: &str = "opt/ktest/authorized_keys";
+
+fn key_path(config: &Config) -> PathBuf {
+    config.make.out_dir().join("ssh").join("id_ed25519")
+}
+
+/// File in the VM instance dir recording the host port forwarded to ssh.
+pub fn port_file(config: &Config) -> PathBuf {
+    config.make.out_dir().join("vm").join("ssh-port")
+}
+
+/// Public half of the key ktest logs into guests with, generated on first use.
+pub fn public_key(config: &Config) -> Result<PathBuf> {
+    let key = key_path(config);
+    if !key.exists() {
+        std::fs::create_dir_all(key.parent().unwrap()).context("Failed to create ssh dir")?;
+
+        debug!("Running ssh-keygen for {}", key.display());
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "ktest", "-f"])
+            .arg(&key)
+            .status()
+            .context("Failed to execute ssh-keygen")?;
+        if !status.success() {
+            return Err(Error::new("Failed to generate ssh key").set_exit_code(status.code()));
+        }
+    }
+
+    Ok(key.with_extension("pub"))
+}
+
+/// How often a new port is tried when qemu fails to bind the previous one.
+const FORWARD_ATTEMPTS: usize = 10;
+
+/// How long qemu gets to create its monitor socket.
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A host port forwarded to ssh in a running VM, recorded for `ktest ssh`.
+#[derive(Debug)]
+pub struct PortForward {
+    monitor: PathBuf,
+    port_file: PathBuf,
+    port: Option<u16>,
+}
+
+impl PortForward {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            monitor: crate::monitor::socket(config),
+            port_file: port_file(config),
+            port: None,
+        }
+    }
+
+    /// Forward a free host port to the guest through the monitor of the VM
+    /// that was just started.
+    ///
+    /// The port is only free when it is picked, so when something else binds it
+    /// before qemu does another one is tried.
+    pub fn start(&mut self) -> Result<u16> {
+        self.wait_for_monitor()?;
+
+        for _ in 0..FORWARD_ATTEMPTS {
+            // let the kernel pick a free port, qemu binds it right after
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .and_then(|l| l.local_addr())
+                .context("Failed to find a free port for ssh")?
+                .port();
+
+            let rule = format!("hostfwd_add net0 tcp:127.0.0.1:{port}-:22");
+            // hostfwd_add only prints anything on errors
+            match crate::monitor::hmp_at(&self.monitor, &rule) {
+                Ok(out) if out.trim().is_empty() => {}
+                Ok(out) => {
+                    debug!("Failed to forward port {port}: {}", out.trim());
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Failed to forward port {port}: {e}");
+                    continue;
+                }
+            }
+
+            std::fs::write(&self.port_file, format!("{port}\n"))
+                .context("Failed to record ssh port")?;
+            debug!("Forwarding port {port} to ssh in the guest");
+            self.port = Some(port);
+            return Ok(port);
+        }
+
+        Err(Error::new(
+            "Failed to forward a host port to ssh in the guest",
+        ))
+    }
+
+    /// Forget the port once the VM is gone, so `ktest ssh` can't end up
+    /// connecting to whatever binds it next.
+    pub fn stop(&mut self) {
+        if self.port.take().is_some() {
+            drop(std::fs::remove_file(&self.port_file));
+        }
+    }
+
+    fn wait_for_monitor(&self) -> Result {
+        let deadline = Instant::now() + MONITOR_TIMEOUT;
+        loop {
+            match UnixStream::connect(&self.monitor) {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(e).context("Failed to connect to qemu monitor");
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+}
+
+/// Run ssh into the VM, with an interactive shell if `args` is empty.
+#[instrument(level = "debug", skip(config))]
+pub fn ssh(config: &Config, args: &[String]) -> Result {
+    let port = std::fs::read_to_string(port_file(config))
+        .context("No VM with networking is running, boot one with --network user")?;
+
+    let mut cmd = Command::new("ssh");
+    cmd.arg("-i")
+        .arg(key_path(config))
+        .arg("-p")
+        .arg(port.trim())
+        .args([
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-o",
+            "UserKnownHostsFile=/dev/null",
+            "-o",
+            "LogLevel=ERROR",
+            "root@127.0.0.1",
+        ])
+        .args(args);
+
+    debug!("Running ssh on port {}", port.trim());
+    let status = cmd.status().context("Failed to execute ssh")?;
+    if !status.success() {
+        return Err(Error::new("ssh failed").set_exit_code(status.code()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::*;
+
+/// Normalized warnings and how often each of them occurs.
+pub type Warnings = BTreeMap<String, usize>;
+
+/// A warning without its line and column, so unrelated changes moving code
+/// around do not turn known warnings into new ones.
+pub fn normalize(diagnostic: &Diagnostic) -> String {
+    format!(
+        "{}: {}",
+        diagnostic.location.file.display(),
+        diagnostic.message
+    )
+}
+
+/// The normalized warnings in `diagnostics`.
+pub fn warnings(diagnostics: &[Diagnostic]) -> Warnings {
+    count(
+        diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .map(normalize),
+    )
+}
+
+fn count(warnings: impl IntoIterator<Item = String>) -> Warnings {
+    let mut ret = Warnings::new();
+    for warning in warnings {
+        *ret.entry(warning).or_default() += 1;
+    }
+    ret
+}
+
+/// Read a baseline written by [`write_baseline`], one warning per line and
+/// repeated as often as it occurs.
+pub fn read_baseline(path: &Path) -> Result<Warnings> {
+    let content = std::fs::read_to_string(path).context(format!(
+        "Failed to read warnings baseline {}",
+        path.display()
+    ))?;
+    Ok(count(
+        content
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(str::to_string),
+    ))
+}
+
+pub fn write_baseline(path: &Path, warnings: &Warnings) -> Result {
+    let mut content = String::new();
+    for (warning, n) in warnings {
+        for _ in 0..*n {
+            content.push_str(warning);
+            content.push('\n');
+        }
+    }
+    std::fs::write(path, content).context(format!(
+        "Failed to write warnings baseline {}",
+        path.display()
+    ))?;
+    info!(
+        "Recorded {} warnings in {}",
+        warnings.values().sum::<usize>(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Warnings of the current build that are not in the baseline, once for every
+/// occurrence more than the baseline has.
+pub fn new_warnings<'a>(current: &'a Warnings, baseline: &Warnings) -> Vec<&'a String> {
+    current
+        .iter()
+        .flat_map(|(warning, n)| {
+            let known = baseline.get(warning).copied().unwrap_or(0);
+            std::iter::repeat_n(warning, n.saturating_sub(known))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Location;
+    use std::path::PathBuf;
+
+    fn diagnostic(file: &str, line: u32, severity: Severity, message: &str) -> Diagnostic {
+        Diagnostic {
+            location: Location {
+                file: PathBuf::from(file),
+                line,
+                column: Some(1),
+            },
+            severity,
+            message: message.to_string(),
+            include_stack: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn normalize_drops_position() {
+        let a = diagnostic("mm/slab.c", 10, Severity::Warning, "unused variable 'x'");
+        let b = diagnostic("mm/slab.c", 42, Severity::Warning, "unused variable 'x'");
+        assert_eq!(normalize(&a), "mm/slab.c: unused variable 'x'");
+        assert_eq!(normalize(&a), normalize(&b));
+    }
+
+    #[test]
+    fn counts_warnings_only() {
+        let warnings = warnings(&[
+            diagnostic("a.c", 1, Severity::Warning, "w"),
+            diagnostic("a.c", 2, Severity::Warning, "w"),
+            diagnostic("a.c", 3, Severity::Error, "e"),
+            diagnostic("a.c", 4, Severity::Note, "n"),
+        ]);
+        assert_eq!(warnings, Warnings::from([("a.c: w".to_string(), 2)]));
+    }
+
+    #[test]
+    fn new_occurrences() {
+        let baseline = count(["a.c: w".to_string(), "b.c: w".to_string()]);
+        let current = count([
+            "a.c: w".to_string(),
+            "a.c: w".to_string(),
+            "a.c: w".to_string(),
+            "c.c: w".to_string(),
+        ]);
+        assert_eq!(
+            new_warnings(&current, &baseline),
+            ["a.c: w", "a.c: w", "c.c: w"]
+        );
+        assert!(new_warnings(&baseline, &baseline).is_empty());
+    }
+
+    #[test]
+    fn baseline_roundtrip() {
+        let path = std::env::temp_dir().join(format!("ktest-baseline-{}", std::process::id()));
+        let warnings = count([
+            "a.c: w".to_string(),
+            "a.c: w".to_string(),
+            "b.c: x".to_string(),
+        ]);
+        write_baseline(&path, &warnings).unwrap();
+        let read = read_baseline(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, warnings);
+    }
+}
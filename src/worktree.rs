@@ -0,0 +1,73 @@
+use crate::config::Config;
+use crate::{Context, Result};
+use std::path::PathBuf;
+use tracing::*;
+
+/// Name of the worktree used for `git_ref`, usable as a file name.
+fn worktree_name(git_ref: &str) -> String {
+    git_ref
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Check out `git_ref` in a worktree under `out_dir` and build from there.
+///
+/// The worktree is created on first use and reused afterwards. Each worktree
+/// gets its own build and install dirs, so the kernel in `kernel_dir` and
+/// builds of other refs are left alone.
+#[instrument(level = "debug", skip(config))]
+pub fn use_ref(config: &mut Config, git_ref: &str) -> Result {
+    let source = PathBuf::from(&config.make.kernel_dir);
+    let commit = crate::git::output(
+        &source,
+        &["rev-parse", "--verify", &format!("{git_ref}^{{commit}}")],
+    )
+    .context(format!("Failed to resolve git ref `{git_ref}`"))?
+    .trim()
+    .to_string();
+
+    let name = worktree_name(git_ref);
+    let worktrees = config.make.out_dir().join("worktrees");
+    std::fs::create_dir_all(&worktrees).context("Failed to create worktree dir")?;
+    let path = worktrees
+        .canonicalize()
+        .context("Failed to resolve worktree dir")?
+        .join(&name);
+    let path_str = path.to_str().context("Invalid worktree path")?;
+
+    if path.join(".git").exists() {
+        debug!("Updating worktree {} to {commit}", path.display());
+        crate::git::output(&path, &["checkout", "--detach", &commit])?;
+    } else {
+        info!("Creating worktree {} for {git_ref}", path.display());
+        // a worktree dir removed by hand is still registered otherwise
+        crate::git::output(&source, &["worktree", "prune"])?;
+        crate::git::output(&source, &["worktree", "add", "--detach", path_str, &commit])?;
+    }
+
+    println!("Using worktree {} at {git_ref} ({commit})", path.display());
+    config.make.kernel_dir = path_str.to_string();
+    config.make.worktree = Some(name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(worktree_name("v6.8-rc1"), "v6.8-rc1");
+        assert_eq!(worktree_name("origin/master"), "origin_master");
+        assert_eq!(worktree_name("HEAD~2"), "HEAD_2");
+        assert_eq!(worktree_name("a b:c^{}"), "a_b_c___");
+    }
+}